@@ -0,0 +1,150 @@
+// Digests of the content of a file, computed from the data as it is sent.
+//
+// The parts of a multipart upload are sent concurrently, the chunks read ahead of the hashed
+// content are kept until what comes before them is hashed. Chunks sent again by a retry are
+// hashed once.
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tardis::{
+    crypto::crypto_digest::algorithm::{Digest, Md5, Sha256},
+    tokio::{fs::File, io::AsyncReadExt as _},
+};
+
+// Size of the reads of `hash_file`
+const HASH_BUF_SIZE: usize = 64 * 1024;
+// Beyond this, the chunks ahead are dropped and the file is hashed again once sent
+const MAX_AHEAD_SIZE: usize = 64 * 1024 * 1024;
+
+/// Hex sha256 and, if asked, md5 of the content.
+pub type Digests = (String, Option<String>);
+
+/// Shared by the body streams of the puts of a file.
+pub type SharedHasher = Arc<Mutex<ContentHasher>>;
+
+pub struct ContentHasher {
+    sha256: Sha256,
+    md5: Option<Md5>,
+    // length of the content hashed so far
+    hashed: u64,
+    // chunks read past `hashed`, by offset
+    ahead: BTreeMap<u64, Vec<u8>>,
+    ahead_size: usize,
+    gave_up: bool,
+}
+
+impl ContentHasher {
+    pub fn new(md5: bool) -> Self {
+        ContentHasher {
+            sha256: Sha256::new(),
+            md5: md5.then(Md5::new),
+            hashed: 0,
+            ahead: BTreeMap::new(),
+            ahead_size: 0,
+            gave_up: false,
+        }
+    }
+
+    pub fn shared(md5: bool) -> SharedHasher {
+        Arc::new(Mutex::new(ContentHasher::new(md5)))
+    }
+
+    /// Hash `chunk` read at `offset` of the file.
+    pub fn update(&mut self, offset: u64, chunk: &[u8]) {
+        if self.gave_up {
+            return;
+        }
+        if offset > self.hashed {
+            if self.ahead_size + chunk.len() > MAX_AHEAD_SIZE {
+                log::warn!("too much data ahead of the hashed content, hashed again once sent");
+                self.gave_up = true;
+                self.ahead.clear();
+                self.ahead_size = 0;
+                return;
+            }
+            // a retry may read shorter chunks
+            let kept = self.ahead.get(&offset).map_or(0, |kept| kept.len());
+            if chunk.len() > kept {
+                self.ahead_size += chunk.len() - kept;
+                self.ahead.insert(offset, chunk.to_vec());
+            }
+            return;
+        }
+        self.hash_from(offset, chunk);
+        while let Some(entry) = self.ahead.first_entry() {
+            if *entry.key() > self.hashed {
+                break;
+            }
+            let (offset, chunk) = entry.remove_entry();
+            self.ahead_size -= chunk.len();
+            self.hash_from(offset, &chunk);
+        }
+    }
+
+    // `offset` is not past `hashed`, the already hashed head of the chunk is skipped
+    fn hash_from(&mut self, offset: u64, chunk: &[u8]) {
+        let skip = (self.hashed - offset).min(chunk.len() as u64) as usize;
+        let data = &chunk[skip..];
+        self.sha256.update(data);
+        if let Some(md5) = &mut self.md5 {
+            md5.update(data);
+        }
+        self.hashed += data.len() as u64;
+    }
+
+    pub fn has_md5(&self) -> bool {
+        self.md5.is_some()
+    }
+
+    /// The digests once the `size` bytes of the file are hashed.
+    pub fn digests(&self, size: u64) -> Option<Digests> {
+        if self.gave_up || self.hashed != size {
+            return None;
+        }
+        Some((
+            hex::encode(self.sha256.clone().finalize()),
+            self.md5.clone().map(|md5| hex::encode(md5.finalize())),
+        ))
+    }
+}
+
+/// Digests of the whole file in a single streaming read, for those needed before the data is sent.
+pub async fn hash_file(path: &Path, md5: bool) -> std::io::Result<Digests> {
+    let mut file = File::open(path).await?;
+    let mut hasher = ContentHasher::new(md5);
+    let mut buf = vec![0; HASH_BUF_SIZE];
+    loop {
+        let len = file.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        let offset = hasher.hashed;
+        hasher.update(offset, &buf[..len]);
+    }
+    let size = hasher.hashed;
+    Ok(hasher.digests(size).expect("can't be here"))
+}
+
+#[test]
+fn test_content_hasher() {
+    let content = (0..100u8).collect::<Vec<_>>();
+    let expected = (
+        hex::encode(Sha256::digest(&content)),
+        Some(hex::encode(Md5::digest(&content))),
+    );
+    let mut hasher = ContentHasher::new(true);
+    // parts sent concurrently, the second one retried
+    hasher.update(40, &content[40..70]);
+    hasher.update(70, &content[70..100]);
+    hasher.update(40, &content[40..60]);
+    assert_eq!(hasher.digests(100), None);
+    hasher.update(0, &content[0..25]);
+    hasher.update(25, &content[25..40]);
+    hasher.update(40, &content[40..70]);
+    hasher.update(0, &content[0..10]);
+    assert_eq!(hasher.digests(100), Some(expected));
+    assert_eq!(hasher.ahead_size, 0);
+    assert_eq!(hasher.digests(99), None);
+}
@@ -0,0 +1,138 @@
+// Hosts the uploader may send requests to, see `ProcessorConfig::allowed_hosts`.
+//
+// A pattern is either a host, optionally with a port and a leading `*.` matching its subdomains
+// (`uploads.example.com`, `*.example.com`, `127.0.0.1:8080`), or a url prefix
+// (`https://api.example.com/upload/`) matching whole path segments.
+// Nothing is allowed until the list is configured.
+use tardis::basic::{error::TardisError, result::TardisResult};
+
+use crate::FileUploadProcessParams;
+
+// Redirects followed by the clients of `client`
+const MAX_REDIRECTS: usize = 10;
+
+/// Reject `url` if its host is not allowed, an empty list allows no host.
+pub fn check(url: &str, allowed_hosts: &[String]) -> TardisResult<()> {
+    if allowed_hosts.is_empty() {
+        return Err(TardisError::forbidden(
+            "no allowed hosts configured, `csm.processor.allowed_hosts` must be set",
+            "403-host-not-configured",
+        ));
+    }
+    let parsed = reqwest::Url::parse(url).map_err(|e| {
+        TardisError::bad_request(&format!("invalid url {url}:{e}"), "400-host-invalid-url")
+    })?;
+    if allowed_hosts
+        .iter()
+        .any(|pattern| matches(pattern, &parsed))
+    {
+        Ok(())
+    } else {
+        Err(TardisError::forbidden(
+            &format!("the host of {url} is not allowed"),
+            "403-host-not-allowed",
+        ))
+    }
+}
+
+/// Check the urls of the upload params, before anything is sent.
+pub fn check_params(
+    upload: &FileUploadProcessParams,
+    allowed_hosts: &[String],
+) -> TardisResult<()> {
+    check(&upload.upload_metadata_url, allowed_hosts)?;
+    if let Some(digest_url) = upload
+        .upload_hash
+        .as_ref()
+        .and_then(|hash| hash.digest_url.as_ref())
+    {
+        check(digest_url, allowed_hosts)?;
+    }
+    for url in [
+        &upload.upload_abort_url,
+        &upload.upload_dedup_url,
+        &upload.check_key_url,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        check(url, allowed_hosts)?;
+    }
+    Ok(())
+}
+
+/// Http client whose redirects are checked against the allow-list as well.
+pub fn client(allowed_hosts: Vec<String>) -> TardisResult<reqwest::Client> {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+            match check(attempt.url().as_str(), &allowed_hosts) {
+                Ok(()) => attempt.follow(),
+                Err(e) => attempt.error(e.message),
+            }
+        }))
+        .build()
+        .map_err(|e| TardisError::io_error(&format!("build client fail:{e}"), "error"))
+}
+
+fn matches(pattern: &str, url: &reqwest::Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    if pattern.contains("://") {
+        return reqwest::Url::parse(pattern).is_ok_and(|prefix| {
+            prefix.scheme() == url.scheme()
+                && prefix.host_str() == Some(host)
+                && prefix.port_or_known_default() == url.port_or_known_default()
+                && url.path().strip_prefix(prefix.path()).is_some_and(|rest| {
+                    prefix.path().ends_with('/') || rest.is_empty() || rest.starts_with('/')
+                })
+        });
+    }
+    let (pattern_host, port) = match pattern.rsplit_once(':') {
+        Some((pattern_host, port)) if port.parse::<u16>().is_ok() => {
+            (pattern_host, port.parse::<u16>().ok())
+        }
+        _ => (pattern, None),
+    };
+    if port.is_some() && port != url.port_or_known_default() {
+        return false;
+    }
+    match pattern_host.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(&domain.to_ascii_lowercase())
+            .is_some_and(|sub| sub.ends_with('.')),
+        None => pattern_host.eq_ignore_ascii_case(host),
+    }
+}
+
+#[test]
+fn test_check() {
+    let allowed_hosts = [
+        "uploads.example.com",
+        "*.storage.example.com",
+        "127.0.0.1:8080",
+        "https://api.example.com/upload/",
+    ]
+    .map(String::from);
+
+    assert!(check("https://uploads.example.com/x", &[]).is_err());
+    assert!(check("https://Uploads.Example.com/x?a=1", &allowed_hosts).is_ok());
+    assert!(check("https://b.a.storage.example.com/x", &allowed_hosts).is_ok());
+    assert!(check("http://127.0.0.1:8080/x", &allowed_hosts).is_ok());
+    assert!(check("https://api.example.com/upload/meta", &allowed_hosts).is_ok());
+    assert!(check("https://storage.example.com/x", &allowed_hosts).is_err());
+    assert!(check("https://xstorage.example.com/x", &allowed_hosts).is_err());
+    assert!(check("https://uploads.example.com.evil.com/x", &allowed_hosts).is_err());
+    assert!(check("http://127.0.0.1:9090/x", &allowed_hosts).is_err());
+    assert!(check("http://api.example.com/upload/meta", &allowed_hosts).is_err());
+    assert!(check("https://api.example.com/admin", &allowed_hosts).is_err());
+    assert!(check("https://api.example.com/upload", &allowed_hosts).is_err());
+    let allowed_hosts = ["https://api.example.com/upload".to_string()];
+    assert!(check("https://api.example.com/upload", &allowed_hosts).is_ok());
+    assert!(check("https://api.example.com/upload/meta", &allowed_hosts).is_ok());
+    assert!(check("https://api.example.com/upload-evil", &allowed_hosts).is_err());
+    assert!(check("not a url", &allowed_hosts).is_err());
+}
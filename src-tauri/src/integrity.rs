@@ -0,0 +1,172 @@
+use base64::{engine::general_purpose, Engine as _};
+use tardis::web::reqwest::header::{HeaderMap, CONTENT_LENGTH, ETAG};
+
+use crate::uploader::UploadFileInfo;
+
+const CHECKSUM_SHA256_HEADER: &str = "x-amz-checksum-sha256";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CheckFailure {
+    Mismatch(String),
+    // none of the headers could be compared with the local file
+    Unverifiable,
+}
+
+impl std::fmt::Display for CheckFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckFailure::Mismatch(message) => write!(f, "{message}"),
+            CheckFailure::Unverifiable => write!(
+                f,
+                "unverifiable, the storage reports no ETag, {CHECKSUM_SHA256_HEADER} or Content-Length of the object"
+            ),
+        }
+    }
+}
+
+/// Compare what the storage reports of an uploaded object with the local file.
+/// `has_length` is false for put responses, whose length is the one of the response body.
+pub fn check_object(
+    info: &UploadFileInfo,
+    headers: &HeaderMap,
+    has_length: bool,
+) -> Result<(), CheckFailure> {
+    let mut checked = false;
+    if has_length {
+        if let Some(length) = header(headers, CONTENT_LENGTH.as_str()) {
+            if length.parse::<u64>().ok() != Some(info.size) {
+                return Err(CheckFailure::Mismatch(format!(
+                    "size mismatch, local {} remote {length}",
+                    info.size
+                )));
+            }
+            checked = true;
+        }
+    }
+    if let (Some(sha256), Some(checksum)) = (&info.sha256, header(headers, CHECKSUM_SHA256_HEADER))
+    {
+        let remote = general_purpose::STANDARD
+            .decode(checksum)
+            .map(hex::encode)
+            .unwrap_or_default();
+        if !remote.eq_ignore_ascii_case(sha256) {
+            return Err(CheckFailure::Mismatch(format!(
+                "sha256 mismatch, local {sha256} remote {checksum}"
+            )));
+        }
+        checked = true;
+    }
+    if let (Some(md5), Some(etag)) = (&info.md5, header(headers, ETAG.as_str())) {
+        let etag = etag.trim_start_matches("W/").trim_matches('"');
+        // etags of multipart or encrypted objects are not the md5 of the content
+        if is_md5(etag) {
+            if !etag.eq_ignore_ascii_case(md5) {
+                return Err(CheckFailure::Mismatch(format!(
+                    "ETag mismatch, local md5 {md5} remote {etag}"
+                )));
+            }
+            checked = true;
+        }
+    }
+    if checked {
+        Ok(())
+    } else {
+        Err(CheckFailure::Unverifiable)
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn is_md5(etag: &str) -> bool {
+    etag.len() == 32 && etag.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[test]
+fn test_check_object() {
+    use std::path::PathBuf;
+    let info = UploadFileInfo {
+        id: String::new(),
+        name: String::new(),
+        relative_path: PathBuf::new(),
+        size: 3,
+        mime_type: "text/plain".to_string(),
+        mime_type_by_content: None,
+        mime_type_by_extension: None,
+        sha256: Some(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string(),
+        ),
+        md5: Some("900150983cd24fb0d6963f7d28e17f72".to_string()),
+        link_target: None,
+        path: PathBuf::new(),
+    };
+    let headers = |pairs: &[(&'static str, &'static str)]| {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    };
+    assert_eq!(
+        check_object(&info, &headers(&[]), true),
+        Err(CheckFailure::Unverifiable)
+    );
+    assert!(check_object(
+        &info,
+        &headers(&[
+            ("etag", "\"900150983CD24FB0D6963F7D28E17F72\""),
+            (
+                "x-amz-checksum-sha256",
+                "ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0="
+            ),
+            ("content-length", "3"),
+        ]),
+        true
+    )
+    .is_ok());
+    // the length of a put response is the one of its body
+    assert_eq!(
+        check_object(&info, &headers(&[("content-length", "0")]), false),
+        Err(CheckFailure::Unverifiable)
+    );
+    assert!(check_object(&info, &headers(&[("content-length", "3")]), true).is_ok());
+    assert!(check_object(&info, &headers(&[("content-length", "2")]), true).is_err());
+    assert!(check_object(
+        &info,
+        &headers(&[("etag", "\"00000000000000000000000000000000\"")]),
+        true
+    )
+    .is_err());
+    // etag of a multipart object
+    assert_eq!(
+        check_object(
+            &info,
+            &headers(&[("etag", "\"00000000000000000000000000000000-2\"")]),
+            true
+        ),
+        Err(CheckFailure::Unverifiable)
+    );
+    // no local md5 to compare the etag with
+    let no_md5 = UploadFileInfo {
+        md5: None,
+        ..info.clone()
+    };
+    assert_eq!(
+        check_object(
+            &no_md5,
+            &headers(&[("etag", "\"900150983cd24fb0d6963f7d28e17f72\"")]),
+            false
+        ),
+        Err(CheckFailure::Unverifiable)
+    );
+    assert!(check_object(
+        &info,
+        &headers(&[(
+            "x-amz-checksum-sha256",
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+        )]),
+        true
+    )
+    .is_err());
+}
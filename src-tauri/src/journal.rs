@@ -0,0 +1,395 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+use tardis::{
+    basic::{error::TardisError, result::TardisResult},
+    tokio::{
+        fs::{self, File, OpenOptions},
+        io::AsyncWriteExt as _,
+    },
+    TardisFuns,
+};
+use tauri::{Manager as _, Window};
+
+use crate::{
+    uploader::{UploadFileInfo, UploadSource},
+    FileUploadProcessParams,
+};
+
+const JOURNAL_DIR: &str = "journals";
+const BATCH_FILE: &str = "batch.json";
+const FILES_FILE: &str = "files.log";
+const PROGRESS_FILE: &str = "progress.log";
+// Created once all the files of the batch are in `files.log`
+const SCANNED_FILE: &str = "scanned";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum UploadFileState {
+    Success,
+    Fail,
+    // skipped by the user, not uploaded again when resumed
+    Skipped,
+}
+
+// One line of `files.log`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct JournalFile {
+    // paths of `info` are sent lossy, they are kept escaped here
+    #[serde(with = "crate::path_codec::lossless")]
+    path: PathBuf,
+    #[serde(with = "crate::path_codec::lossless")]
+    relative_path: PathBuf,
+    #[serde(default, with = "crate::path_codec::lossless::option")]
+    link_target: Option<PathBuf>,
+    info: UploadFileInfo,
+}
+
+// The params are not kept, their fixed headers carry tokens, a resumed batch uses the ones of the new link
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct JournalBatch {
+    files_uris: Vec<String>,
+}
+
+// One line of `progress.log`
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalRecord {
+    id: String,
+    state: UploadFileState,
+}
+
+struct JournalState {
+    batch: JournalBatch,
+    // in scan order, without duplicates
+    files: Vec<JournalFile>,
+    states: HashMap<String, UploadFileState>,
+    scanned: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ResumableUploadResp {
+    pub files_uris: Vec<String>,
+    pub total_file_numbers: usize,
+    pub remaining_file_numbers: usize,
+    pub remaining_file_size: u64,
+}
+
+/// Per-batch journal kept in the app data dir so that an interrupted batch can be resumed.
+///
+/// A batch is identified by its upload target, so a new deep link to the same target
+/// (e.g. with a refreshed token) can still resume it. Files are appended to the file list
+/// as they are scanned, every finished file is appended to the progress log.
+pub struct UploadJournal {
+    dir: PathBuf,
+    files: File,
+    progress: File,
+}
+
+impl UploadJournal {
+    pub async fn create(
+        window: &Window,
+        params: &FileUploadProcessParams,
+        files_uris: &[String],
+    ) -> TardisResult<Self> {
+        let dir = journal_dir(window, params)?;
+        if fs::try_exists(&dir).await? {
+            fs::remove_dir_all(&dir).await?;
+        }
+        fs::create_dir_all(&dir).await?;
+        let batch = JournalBatch {
+            files_uris: files_uris.to_vec(),
+        };
+        fs::write(
+            dir.join(BATCH_FILE),
+            TardisFuns::json.obj_to_string(&batch)?,
+        )
+        .await?;
+        let files = File::create(dir.join(FILES_FILE)).await?;
+        let progress = File::create(dir.join(PROGRESS_FILE)).await?;
+        Ok(UploadJournal {
+            dir,
+            files,
+            progress,
+        })
+    }
+
+    /// Open the journal of an unfinished batch, returns where the files neither uploaded successfully
+    /// nor skipped come from: the recorded file list, or a new scan if the batch was stopped while scanning.
+    pub async fn open(
+        window: &Window,
+        params: &FileUploadProcessParams,
+    ) -> TardisResult<Option<(Self, UploadSource)>> {
+        let dir = journal_dir(window, params)?;
+        let Some(state) = load(&dir).await? else {
+            return Ok(None);
+        };
+        let files = append(&dir.join(FILES_FILE)).await?;
+        let progress = append(&dir.join(PROGRESS_FILE)).await?;
+        let source = if state.scanned {
+            UploadSource::Files(remaining_files(state.files, &state.states))
+        } else {
+            UploadSource::Scan {
+                files_uris: state.batch.files_uris,
+                skip_ids: state
+                    .states
+                    .into_iter()
+                    .filter(|(_, state)| is_done(*state))
+                    .map(|(id, _)| id)
+                    .collect(),
+            }
+        };
+        Ok(Some((
+            UploadJournal {
+                dir,
+                files,
+                progress,
+            },
+            source,
+        )))
+    }
+
+    pub async fn add_file(&mut self, info: &UploadFileInfo) {
+        let file = JournalFile {
+            path: info.path.clone(),
+            relative_path: info.relative_path.clone(),
+            link_target: info.link_target.clone(),
+            info: info.clone(),
+        };
+        append_line(&mut self.files, &file).await;
+    }
+
+    pub async fn finish_scan(&self) {
+        if let Err(e) = fs::write(self.dir.join(SCANNED_FILE), "").await {
+            log::error!("write upload journal fail:{e}");
+        }
+    }
+
+    pub async fn record(&mut self, id: &str, state: UploadFileState) {
+        let record = JournalRecord {
+            id: id.to_string(),
+            state,
+        };
+        append_line(&mut self.progress, &record).await;
+    }
+
+    pub async fn remove(self) {
+        drop(self.files);
+        drop(self.progress);
+        if let Err(e) = fs::remove_dir_all(&self.dir).await {
+            log::error!("remove upload journal fail:{e}");
+        }
+    }
+}
+
+pub async fn get_resumable(
+    window: &Window,
+    params: &FileUploadProcessParams,
+) -> TardisResult<Option<ResumableUploadResp>> {
+    let Some(state) = load(&journal_dir(window, params)?).await? else {
+        return Ok(None);
+    };
+    // only the files found before the batch was stopped are known if it was stopped while scanning
+    let total_file_numbers = state.files.len();
+    let files_uris = state.batch.files_uris;
+    let remaining = remaining_files(state.files, &state.states);
+    if remaining.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(ResumableUploadResp {
+        files_uris,
+        total_file_numbers,
+        remaining_file_numbers: remaining.len(),
+        remaining_file_size: remaining.iter().map(|info| info.size).sum(),
+    }))
+}
+
+pub async fn discard(window: &Window, params: &FileUploadProcessParams) -> TardisResult<()> {
+    let dir = journal_dir(window, params)?;
+    if fs::try_exists(&dir).await? {
+        fs::remove_dir_all(&dir).await?;
+    }
+    Ok(())
+}
+
+fn journal_dir(window: &Window, params: &FileUploadProcessParams) -> TardisResult<PathBuf> {
+    let app_data_dir = window
+        .app_handle()
+        .path()
+        .app_data_dir()
+        .map_err(|e| TardisError::io_error(&format!("app data dir error:{e}"), "error"))?;
+    let batch_key = Sha256::digest(
+        format!(
+            "{}\n{}\n{}",
+            params.target_kind_key, params.target_obj_key, params.upload_metadata_url
+        )
+        .as_bytes(),
+    );
+    Ok(app_data_dir
+        .join(JOURNAL_DIR)
+        .join(hex::encode(&batch_key[..16])))
+}
+
+async fn load(dir: &Path) -> TardisResult<Option<JournalState>> {
+    let batch_path = dir.join(BATCH_FILE);
+    if !fs::try_exists(&batch_path).await? {
+        return Ok(None);
+    }
+    let batch =
+        TardisFuns::json.str_to_obj::<JournalBatch>(&fs::read_to_string(&batch_path).await?)?;
+    let mut files = Vec::new();
+    // a resumed scan finds the files recorded by the previous run again
+    let mut file_ids = HashSet::new();
+    for file in read_lines::<JournalFile>(&dir.join(FILES_FILE)).await? {
+        if file_ids.insert(file.info.id.clone()) {
+            files.push(file);
+        }
+    }
+    let states = read_lines::<JournalRecord>(&dir.join(PROGRESS_FILE))
+        .await?
+        .into_iter()
+        .map(|record| (record.id, record.state))
+        .collect();
+    Ok(Some(JournalState {
+        batch,
+        files,
+        states,
+        scanned: fs::try_exists(dir.join(SCANNED_FILE)).await?,
+    }))
+}
+
+async fn read_lines<T: DeserializeOwned>(path: &Path) -> TardisResult<Vec<T>> {
+    if !fs::try_exists(path).await? {
+        return Ok(vec![]);
+    }
+    // the last line may be incomplete if the app was killed while writing it
+    Ok(fs::read_to_string(path)
+        .await?
+        .lines()
+        .filter_map(|line| TardisFuns::json.str_to_obj::<T>(line).ok())
+        .collect())
+}
+
+async fn append(path: &Path) -> TardisResult<File> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?)
+}
+
+async fn append_line<T: Serialize>(file: &mut File, value: &T) {
+    let Ok(mut line) = TardisFuns::json.obj_to_string(value) else {
+        return;
+    };
+    line.push('\n');
+    if let Err(e) = file.write_all(line.as_bytes()).await {
+        log::error!("write upload journal fail:{e}");
+        return;
+    }
+    let _ = file.flush().await;
+}
+
+// Files in these states are not uploaded again when the batch is resumed
+fn is_done(state: UploadFileState) -> bool {
+    matches!(state, UploadFileState::Success | UploadFileState::Skipped)
+}
+
+fn remaining_files(
+    files: Vec<JournalFile>,
+    states: &HashMap<String, UploadFileState>,
+) -> Vec<UploadFileInfo> {
+    files
+        .into_iter()
+        .filter(|file| {
+            !states
+                .get(&file.info.id)
+                .is_some_and(|state| is_done(*state))
+        })
+        .map(|file| UploadFileInfo {
+            path: file.path,
+            relative_path: file.relative_path,
+            link_target: file.link_target,
+            ..file.info
+        })
+        .collect()
+}
+
+#[test]
+fn test_load() {
+    let info = |id: &str| UploadFileInfo {
+        id: id.to_string(),
+        name: format!("{id}.txt"),
+        relative_path: PathBuf::from(format!("docs/{id}.txt")),
+        size: 10,
+        mime_type: "text/plain".to_string(),
+        mime_type_by_content: None,
+        mime_type_by_extension: None,
+        sha256: None,
+        md5: None,
+        link_target: None,
+        path: PathBuf::from(format!("/home/docs/{id}.txt")),
+    };
+    let line = |id: &str| {
+        let info = info(id);
+        TardisFuns::json
+            .obj_to_string(&JournalFile {
+                path: info.path.clone(),
+                relative_path: info.relative_path.clone(),
+                link_target: None,
+                info,
+            })
+            .unwrap()
+    };
+    let dir = std::env::temp_dir().join(format!("file-processor-journal-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join(BATCH_FILE), r#"{"files_uris":["/home/docs"]}"#).unwrap();
+    // a resumed scan records "a" again, the app was killed while writing the last line
+    let truncated = line("d");
+    std::fs::write(
+        dir.join(FILES_FILE),
+        format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            line("a"),
+            line("b"),
+            line("c"),
+            line("e"),
+            line("a"),
+            &truncated[..truncated.len() / 2]
+        ),
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join(PROGRESS_FILE),
+        "{\"id\":\"a\",\"state\":\"Success\"}\n{\"id\":\"b\",\"state\":\"Fail\"}\n{\"id\":\"c\",\"state\":\"Skipped\"}\n{\"id\":\"e\",\"sta",
+    )
+    .unwrap();
+    let runtime = tardis::tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let state = runtime.block_on(load(&dir)).unwrap().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(state.batch.files_uris, vec!["/home/docs".to_string()]);
+    assert_eq!(
+        state
+            .files
+            .iter()
+            .map(|file| file.info.id.as_str())
+            .collect::<Vec<_>>(),
+        vec!["a", "b", "c", "e"]
+    );
+    assert_eq!(state.states.len(), 3);
+    assert!(!state.scanned);
+    let remaining = remaining_files(state.files, &state.states);
+    assert_eq!(
+        remaining
+            .iter()
+            .map(|info| info.id.as_str())
+            .collect::<Vec<_>>(),
+        vec!["b", "e"]
+    );
+    assert_eq!(remaining[0].path, PathBuf::from("/home/docs/b.txt"));
+}
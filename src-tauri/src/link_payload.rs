@@ -0,0 +1,157 @@
+// Payload of the deep links, the base64 of the params json.
+//
+// It's read from the `params` query, the path or the host, in that order:
+// `file-processor://upload?params=<payload>`, `file-processor://upload/<payload>` or
+// `file-processor://<payload>`, the host being limited in length and possibly lowercased.
+// Both standard and url safe base64 are accepted, with or without padding, of the json
+// or of its gzip, zlib or raw deflate compression.
+use base64::{engine::general_purpose, Engine as _};
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use percent_encoding::percent_decode_str;
+use std::io::Read;
+use tardis::basic::{error::TardisError, result::TardisResult};
+
+pub const PARAMS_QUERY: &str = "params";
+// Decompressed params beyond this are refused
+const MAX_PAYLOAD_SIZE: u64 = 1024 * 1024;
+
+/// The params json carried by `url`, decoded and decompressed, as signed by the issuer.
+pub fn extract(url: &reqwest::Url) -> TardisResult<Vec<u8>> {
+    let encoded = if let Some((_, params)) = url.query_pairs().find(|(key, _)| key == PARAMS_QUERY)
+    {
+        // `+` of standard base64 is decoded as space from a query
+        params.replace(' ', "+")
+    } else if !url.path().trim_matches('/').is_empty() {
+        percent_decode_str(url.path().trim_matches('/'))
+            .decode_utf8_lossy()
+            .into_owned()
+    } else if let Some(host) = url.host_str().filter(|host| !host.is_empty()) {
+        host.to_string()
+    } else {
+        return Err(TardisError::bad_request(
+            "url not have params!",
+            "400-params-missing",
+        ));
+    };
+    let payload = decode_base64(&encoded)?;
+    decompress(payload)
+}
+
+fn decode_base64(encoded: &str) -> TardisResult<Vec<u8>> {
+    let encoded = encoded
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .map(|c| match c {
+            '+' => '-',
+            '/' => '_',
+            c => c,
+        })
+        .collect::<String>();
+    general_purpose::URL_SAFE_NO_PAD
+        .decode(encoded)
+        .map_err(|e| {
+            TardisError::bad_request(
+                &format!("base64 decode fail:{e}"),
+                "400-params-invalid-base64",
+            )
+        })
+}
+
+// The json starts with `{`, compressed data is hardly ever valid utf-8
+fn decompress(payload: Vec<u8>) -> TardisResult<Vec<u8>> {
+    let first = payload.iter().find(|b| !b.is_ascii_whitespace());
+    if first.is_none_or(|first| *first == b'{') && std::str::from_utf8(&payload).is_ok() {
+        return Ok(payload);
+    }
+    let reader: Box<dyn Read + '_> = if payload.starts_with(&[0x1f, 0x8b]) {
+        Box::new(GzDecoder::new(&payload[..]))
+    } else if is_zlib(&payload) {
+        Box::new(ZlibDecoder::new(&payload[..]))
+    } else {
+        Box::new(DeflateDecoder::new(&payload[..]))
+    };
+    let mut json = Vec::new();
+    reader
+        .take(MAX_PAYLOAD_SIZE + 1)
+        .read_to_end(&mut json)
+        .map_err(|e| {
+            TardisError::bad_request(
+                &format!("decompress params fail:{e}"),
+                "400-params-invalid-compression",
+            )
+        })?;
+    if json.len() as u64 > MAX_PAYLOAD_SIZE {
+        return Err(TardisError::bad_request(
+            "params too large",
+            "400-params-too-large",
+        ));
+    }
+    Ok(json)
+}
+
+// Deflate method and a valid header checksum
+fn is_zlib(payload: &[u8]) -> bool {
+    payload.len() >= 2
+        && payload[0] & 0x0f == 8
+        && ((u16::from(payload[0]) << 8) | u16::from(payload[1])) % 31 == 0
+}
+
+#[test]
+fn test_extract() {
+    use flate2::{
+        write::{DeflateEncoder, GzEncoder, ZlibEncoder},
+        Compression,
+    };
+    use std::io::Write;
+
+    let json = r#"{"title":"上传到：knowledge-74/","upload":null}"#.as_bytes();
+    let url = |link: String| extract(&reqwest::Url::parse(&link).unwrap()).unwrap();
+    let standard = general_purpose::STANDARD.encode(json);
+    let url_safe = general_purpose::URL_SAFE_NO_PAD.encode(json);
+    assert!(standard.contains('+') || standard.contains('/'));
+
+    assert_eq!(url(format!("file-processor://{url_safe}")), json);
+    assert_eq!(url(format!("file-processor://upload/{url_safe}")), json);
+    assert_eq!(url(format!("file-processor:///{url_safe}/")), json);
+    assert_eq!(url(format!("file-processor://upload/{standard}")), json);
+    assert_eq!(
+        url(format!(
+            "file-processor://upload/{}",
+            standard.replace('=', "%3D")
+        )),
+        json
+    );
+    assert_eq!(
+        url(format!("file-processor://upload?params={standard}")),
+        json
+    );
+    assert_eq!(
+        url(format!(
+            "file-processor://upload?params={}&sig=x",
+            standard.replace('+', "%2B").replace('=', "%3D")
+        )),
+        json
+    );
+
+    let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+    gzip.write_all(json).unwrap();
+    let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+    zlib.write_all(json).unwrap();
+    let mut deflate = DeflateEncoder::new(Vec::new(), Compression::default());
+    deflate.write_all(json).unwrap();
+    for compressed in [
+        gzip.finish().unwrap(),
+        zlib.finish().unwrap(),
+        deflate.finish().unwrap(),
+    ] {
+        let encoded = general_purpose::URL_SAFE_NO_PAD.encode(compressed);
+        assert_eq!(
+            url(format!("file-processor://upload?params={encoded}")),
+            json
+        );
+    }
+
+    assert!(
+        extract(&reqwest::Url::parse("file-processor://upload?params=%25%25").unwrap()).is_err()
+    );
+}
@@ -0,0 +1,183 @@
+// Expiry and one-time use of the deep link params, both covered by the link signature.
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::{Read as _, Seek as _, SeekFrom, Write as _},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tardis::{
+    basic::{error::TardisError, result::TardisResult},
+    tokio, TardisFuns,
+};
+
+use crate::FileProcessParams;
+
+const NONCES_FILE: &str = "nonces.log";
+// Tolerated clock difference between the issuer of the link and this machine
+const CLOCK_SKEW_SECS: i64 = 300;
+// Validity of one-time links without expiry from their `issued_at`, their nonces are kept as long
+const MAX_NONCE_AGE_SECS: i64 = 30 * 24 * 3600;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ConsumedNonce {
+    nonce: String,
+    // kept until the link expires
+    expires_at: Option<i64>,
+    // kept for `MAX_NONCE_AGE_SECS` from it if the link doesn't expire
+    #[serde(default)]
+    consumed_at: i64,
+}
+
+/// Unix timestamp in seconds, as `issued_at` and `expires_at`.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Reject expired links and links issued in the future.
+pub fn check_expiry(params: &FileProcessParams, now: i64) -> TardisResult<()> {
+    if let Some(expires_at) = params.expires_at {
+        if expires_at < now {
+            return Err(TardisError::unauthorized(
+                "the link has expired, please open it again from the page",
+                "401-params-expired",
+            ));
+        }
+    }
+    if let Some(issued_at) = params.issued_at {
+        if issued_at > now + CLOCK_SKEW_SECS {
+            return Err(TardisError::unauthorized(
+                "the link is issued in the future, please check the clock of this machine",
+                "401-params-not-yet-valid",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Remember the nonce of the link in `dir`, a link whose nonce was already used is rejected.
+pub async fn consume_nonce(dir: &Path, params: &FileProcessParams, now: i64) -> TardisResult<()> {
+    let Some(nonce) = params.nonce.clone() else {
+        return Ok(());
+    };
+    // its nonce would have to be kept forever
+    if params.expires_at.is_none() && params.issued_at.is_none() {
+        return Err(TardisError::unauthorized(
+            "one-time links need issued_at or expires_at",
+            "401-params-nonce-unbounded",
+        ));
+    }
+    // its nonce would be forgotten while the link is still valid
+    if params.expires_at.is_none()
+        && params
+            .issued_at
+            .is_some_and(|issued_at| issued_at < now - MAX_NONCE_AGE_SECS)
+    {
+        return Err(TardisError::unauthorized(
+            "the link has expired, please open it again from the page",
+            "401-params-expired",
+        ));
+    }
+    let (dir, expires_at) = (dir.to_path_buf(), params.expires_at);
+    tokio::task::spawn_blocking(move || record_nonce(&dir, nonce, expires_at, now))
+        .await
+        .map_err(|e| TardisError::io_error(&format!("record nonce fail:{e}"), "error"))?
+}
+
+// Read, check and rewrite the nonces under an exclusive lock of the file,
+// so that two launches at the same time can't both accept a nonce
+fn record_nonce(dir: &Path, nonce: String, expires_at: Option<i64>, now: i64) -> TardisResult<()> {
+    std::fs::create_dir_all(dir)?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(NONCES_FILE))?;
+    // released when the file is closed
+    file.lock()?;
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    let mut consumed = parse_consumed(&content);
+    // expired links are rejected anyway
+    consumed.retain(|consumed| !is_expired(consumed, now));
+    if consumed.iter().any(|consumed| consumed.nonce == nonce) {
+        return Err(TardisError::unauthorized(
+            "the link has already been used, please open it again from the page",
+            "401-params-replayed",
+        ));
+    }
+    consumed.push(ConsumedNonce {
+        nonce,
+        expires_at,
+        consumed_at: now,
+    });
+    let mut content = String::new();
+    for consumed in &consumed {
+        content.push_str(&TardisFuns::json.obj_to_string(consumed)?);
+        content.push('\n');
+    }
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+// A line may be incomplete if the app was killed while writing the file
+fn parse_consumed(content: &str) -> Vec<ConsumedNonce> {
+    content
+        .lines()
+        .filter_map(|line| TardisFuns::json.str_to_obj::<ConsumedNonce>(line).ok())
+        .collect()
+}
+
+fn is_expired(consumed: &ConsumedNonce, now: i64) -> bool {
+    match consumed.expires_at {
+        Some(expires_at) => expires_at < now,
+        // the link was issued before it was consumed, and at most that long before now
+        None => consumed.consumed_at + MAX_NONCE_AGE_SECS + CLOCK_SKEW_SECS < now,
+    }
+}
+
+#[test]
+fn test_check_expiry() {
+    let params = |issued_at: Option<i64>, expires_at: Option<i64>| FileProcessParams {
+        title: String::new(),
+        upload: None,
+        issued_at,
+        expires_at,
+        nonce: None,
+    };
+    assert!(check_expiry(&params(None, None), 1000).is_ok());
+    assert!(check_expiry(&params(Some(900), Some(1100)), 1000).is_ok());
+    assert!(check_expiry(&params(Some(900), Some(999)), 1000).is_err());
+    assert!(check_expiry(&params(Some(1000 + CLOCK_SKEW_SECS), None), 1000).is_ok());
+    assert!(check_expiry(&params(Some(1001 + CLOCK_SKEW_SECS), None), 1000).is_err());
+    let consumed = |expires_at: Option<i64>| ConsumedNonce {
+        nonce: String::new(),
+        expires_at,
+        consumed_at: 900,
+    };
+    assert!(is_expired(&consumed(Some(999)), 1000));
+    assert!(!is_expired(&consumed(None), 1000));
+    assert!(is_expired(
+        &consumed(None),
+        901 + MAX_NONCE_AGE_SECS + CLOCK_SKEW_SECS
+    ));
+}
+
+#[test]
+fn test_record_nonce() {
+    let dir = std::env::temp_dir().join(format!("file-processor-nonces-{}", std::process::id()));
+    assert!(record_nonce(&dir, "n-1".to_string(), Some(2000), 1000).is_ok());
+    assert!(record_nonce(&dir, "n-2".to_string(), None, 1000).is_ok());
+    assert!(record_nonce(&dir, "n-1".to_string(), Some(2000), 1000).is_err());
+    // forgotten once expired
+    assert!(record_nonce(&dir, "n-1".to_string(), Some(4000), 3000).is_ok());
+    let content = std::fs::read_to_string(dir.join(NONCES_FILE)).unwrap();
+    assert_eq!(parse_consumed(&content).len(), 2);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,149 @@
+// Signatures of the deep link params.
+//
+// The link carries the signature of the decoded (and decompressed) params json in its query:
+// `file-processor://<params>?alg=hmac-sha256|ed25519&sig=<url safe base64>`, see `link_payload`.
+use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::{Signature, Verifier as _, VerifyingKey};
+use hmac::{Hmac, Mac as _};
+use sha2::Sha256;
+use tardis::basic::{error::TardisError, result::TardisResult};
+
+use crate::processor_config::SignatureConfig;
+
+pub const HMAC_SHA256: &str = "hmac-sha256";
+pub const ED25519: &str = "ed25519";
+
+/// Check the signature of `payload` found in the query of `url` against the configured keys.
+pub fn verify(payload: &[u8], url: &reqwest::Url, config: &SignatureConfig) -> TardisResult<()> {
+    let query = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    let Some(signature) = query("sig") else {
+        if config.required {
+            return Err(TardisError::unauthorized(
+                "the link is not signed",
+                "401-params-unsigned",
+            ));
+        }
+        log::warn!("the link is not signed");
+        return Ok(());
+    };
+    let signature = general_purpose::URL_SAFE_NO_PAD
+        .decode(signature.trim_end_matches('='))
+        .map_err(|e| {
+            TardisError::unauthorized(
+                &format!("invalid signature:{e}"),
+                "401-params-bad-signature",
+            )
+        })?;
+    let verified = match query("alg").as_deref().unwrap_or(HMAC_SHA256) {
+        HMAC_SHA256 => config.hmac_secrets.iter().any(|secret| {
+            Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                .map(|mac| mac.chain_update(payload).verify_slice(&signature).is_ok())
+                .unwrap_or(false)
+        }),
+        ED25519 => {
+            let Ok(signature) = Signature::from_slice(&signature) else {
+                return Err(TardisError::unauthorized(
+                    "invalid ed25519 signature",
+                    "401-params-bad-signature",
+                ));
+            };
+            config
+                .ed25519_public_keys
+                .iter()
+                .filter_map(|key| public_key(key))
+                .any(|key| key.verify(payload, &signature).is_ok())
+        }
+        alg => {
+            return Err(TardisError::unauthorized(
+                &format!("unsupported signature algorithm {alg}"),
+                "401-params-bad-signature",
+            ))
+        }
+    };
+    if verified {
+        Ok(())
+    } else {
+        Err(TardisError::unauthorized(
+            "the signature doesn't match, the link may be forged or modified",
+            "401-params-bad-signature",
+        ))
+    }
+}
+
+/// Refuse a config that requires signatures without any key to check them, it would reject every link.
+pub fn check_config(config: &SignatureConfig) -> TardisResult<()> {
+    if config.required && config.hmac_secrets.is_empty() && config.ed25519_public_keys.is_empty() {
+        return Err(TardisError::internal_error(
+            "signature required but no keys configured, set `csm.processor.signature.hmac_secrets` \
+             or `csm.processor.signature.ed25519_public_keys`",
+            "500-signature-not-configured",
+        ));
+    }
+    Ok(())
+}
+
+// Standard base64 of the 32 bytes key
+fn public_key(key: &str) -> Option<VerifyingKey> {
+    let bytes = general_purpose::STANDARD
+        .decode(key)
+        .map_err(|e| log::error!("invalid ed25519 public key {key}:{e}"))
+        .ok()?;
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| log::error!("invalid ed25519 public key {key}"))
+        .ok()?;
+    VerifyingKey::from_bytes(&bytes)
+        .map_err(|e| log::error!("invalid ed25519 public key {key}:{e}"))
+        .ok()
+}
+
+#[test]
+fn test_verify() {
+    use ed25519_dalek::{Signer as _, SigningKey};
+
+    let payload = br#"{"title":"t"}"#;
+    let signing_key = SigningKey::from_bytes(&[7; 32]);
+    let config = SignatureConfig {
+        required: true,
+        hmac_secrets: vec!["secret".to_string()],
+        ed25519_public_keys: vec![
+            general_purpose::STANDARD.encode(signing_key.verifying_key().as_bytes())
+        ],
+    };
+    let url =
+        |query: &str| reqwest::Url::parse(&format!("file-processor://params{query}")).unwrap();
+    let hmac = general_purpose::URL_SAFE_NO_PAD.encode(
+        Hmac::<Sha256>::new_from_slice(b"secret")
+            .unwrap()
+            .chain_update(payload)
+            .finalize()
+            .into_bytes(),
+    );
+    let ed25519 = general_purpose::URL_SAFE_NO_PAD.encode(signing_key.sign(payload).to_bytes());
+
+    assert!(verify(payload, &url(&format!("?sig={hmac}")), &config).is_ok());
+    assert!(verify(
+        payload,
+        &url(&format!("?alg=ed25519&sig={ed25519}")),
+        &config
+    )
+    .is_ok());
+    assert!(verify(br#"{"title":"x"}"#, &url(&format!("?sig={hmac}")), &config).is_err());
+    assert!(verify(payload, &url(&format!("?alg=ed25519&sig={hmac}")), &config).is_err());
+    assert!(verify(payload, &url(""), &config).is_err());
+    assert!(check_config(&config).is_ok());
+    assert!(check_config(&SignatureConfig::default()).is_err());
+    assert!(verify(
+        payload,
+        &url(""),
+        &SignatureConfig {
+            required: false,
+            ..config
+        }
+    )
+    .is_ok());
+}
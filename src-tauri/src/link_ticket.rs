@@ -0,0 +1,108 @@
+// Params by reference, the link carries only a ticket and the endpoint to fetch the params from:
+// `file-processor://ticket?ticket=<id>&endpoint=<url encoded url>[&alg=...&sig=...]`.
+//
+// The ticket is posted as `{"ticket":"<id>"}` to the endpoint, which answers the params json,
+// signed as if it was carried by the link, see `link_signature`.
+use serde::Serialize;
+use std::time::Duration;
+use tardis::{
+    basic::{error::TardisError, result::TardisResult},
+    TardisFuns,
+};
+
+use crate::host_policy;
+
+pub const TICKET_QUERY: &str = "ticket";
+pub const ENDPOINT_QUERY: &str = "endpoint";
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+// Params beyond this are refused
+const MAX_PARAMS_SIZE: usize = 1024 * 1024;
+
+pub struct Ticket {
+    pub id: String,
+    pub endpoint: String,
+}
+
+#[derive(Serialize)]
+struct TicketReq<'a> {
+    ticket: &'a str,
+}
+
+/// The ticket of `url`, none if the params are carried by the link itself.
+pub fn ticket(url: &reqwest::Url) -> TardisResult<Option<Ticket>> {
+    let query = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    match (query(TICKET_QUERY), query(ENDPOINT_QUERY)) {
+        (Some(id), Some(endpoint)) => Ok(Some(Ticket { id, endpoint })),
+        (None, None) => Ok(None),
+        _ => Err(TardisError::bad_request(
+            "the link needs both the ticket and its endpoint",
+            "400-params-invalid-ticket",
+        )),
+    }
+}
+
+/// Fetch the params json of the ticket, the endpoint must match one of `ticket_endpoints`.
+pub async fn fetch(ticket: &Ticket, ticket_endpoints: &[String]) -> TardisResult<Vec<u8>> {
+    if ticket_endpoints.is_empty() {
+        return Err(TardisError::forbidden(
+            "ticket links are disabled, `csm.processor.ticket_endpoints` is not set",
+            "403-params-ticket-disabled",
+        ));
+    }
+    host_policy::check(&ticket.endpoint, ticket_endpoints)?;
+    // a redirect could leave the allowed hosts
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .map_err(|e| TardisError::io_error(&format!("build client fail:{e}"), "error"))?;
+    let fetch_error = |e: reqwest::Error| {
+        TardisError::io_error(&format!("fetch params fail:{e}"), "500-params-fetch-failed")
+    };
+    let mut response = client
+        .post(&ticket.endpoint)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(TardisFuns::json.obj_to_string(&TicketReq { ticket: &ticket.id })?)
+        .send()
+        .await
+        .map_err(fetch_error)?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(TardisError::bad_request(
+            &format!("fetch params fail, status:{status}"),
+            "400-params-ticket-rejected",
+        ));
+    }
+    // stops reading past the limit
+    let mut payload = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(fetch_error)? {
+        if payload.len() + chunk.len() > MAX_PARAMS_SIZE {
+            return Err(TardisError::bad_request(
+                "params too large",
+                "400-params-too-large",
+            ));
+        }
+        payload.extend_from_slice(&chunk);
+    }
+    Ok(payload)
+}
+
+#[test]
+fn test_ticket() {
+    let ticket = |link: &str| ticket(&reqwest::Url::parse(link).unwrap());
+    let found = ticket(
+        "file-processor://ticket?ticket=t-1&endpoint=https%3A%2F%2Fapi.example.com%2Fparams%3Fa%3D1&sig=x",
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(found.id, "t-1");
+    assert_eq!(found.endpoint, "https://api.example.com/params?a=1");
+    assert!(ticket("file-processor://eyJ0aXRsZSI6IiJ9?sig=x")
+        .unwrap()
+        .is_none());
+    assert!(ticket("file-processor://ticket?ticket=t-1").is_err());
+}
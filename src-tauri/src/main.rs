@@ -1,7 +1,7 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use log::info;
 use once_cell::sync::Lazy;
+use processor_config::{ProcessorConfig, DOMAIN_CODE};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::{collections::HashMap, env, sync::Mutex};
@@ -9,14 +9,28 @@ use std::{collections::HashMap, env, sync::Mutex};
 use tardis::config::config_dto::TardisConfig;
 use tardis::TardisFuns;
 use tardis::{basic::result::TardisResult, tokio};
+mod content_hash;
+mod host_policy;
+mod integrity;
+mod journal;
+mod link_payload;
+mod link_replay;
+mod link_signature;
+mod link_ticket;
+mod path_codec;
 mod processor_config;
+mod scan_filter;
 mod tauri;
 mod uploader;
+mod validation;
 
 pub static PARAMS: Lazy<Mutex<FileProcessParams>> = Lazy::new(|| {
     Mutex::new(FileProcessParams {
         title: "".to_string(),
         upload: None,
+        issued_at: None,
+        expires_at: None,
+        nonce: None,
     })
 });
 
@@ -27,29 +41,9 @@ fn get_params() -> FileProcessParams {
 #[tokio::main]
 async fn main() -> TardisResult<()> {
     env::set_var("RUST_LOG", "debug");
-    let args: Vec<String> = env::args().collect();
-    if args.len() > 1 {
-        let raw_params = args[1].as_str();
-        match reqwest::Url::parse(raw_params) {
-            Ok(url) => {
-                let params = tauri::parse_params(&url);
-                info!("params: {:?}", params);
-                let mut params_set = PARAMS.lock().unwrap();
-                check_key(
-                    params
-                        .upload
-                        .clone()
-                        .map(|p| p.target_kind_key)
-                        .unwrap_or_default(),
-                    params.upload.clone().and_then(|p| p.check_key_url),
-                    params.upload.clone().and_then(|p| p.check_key),
-                )
-                .await;
-                *params_set = params;
-            }
-            Err(_) => log::error!("parse url fail!:{raw_params}"),
-        }
-    } else {
+    // verified and applied once the config is loaded, see `tauri::load_params`
+    let link = env::args().nth(1);
+    if link.is_none() {
         // mock
         let mut params_set = PARAMS.lock().unwrap();
         *params_set = FileProcessParams {
@@ -63,8 +57,22 @@ async fn main() -> TardisResult<()> {
                 upload_metadata_rename_filed: None,
                 upload_fixed_metadata: None,
                 upload_fixed_headers: None,
+                upload_multipart: None,
+                upload_abort_url: None,
+                upload_include: None,
+                upload_exclude: None,
+                upload_use_ignore_files: None,
+                upload_symlink: None,
+                upload_mime_source: None,
+                upload_validation: None,
+                upload_hash: None,
+                upload_dedup_url: None,
+                upload_verify: None,
                 target_version: String::new(),
             }),
+            issued_at: None,
+            expires_at: None,
+            nonce: None,
         };
     }
 
@@ -75,7 +83,7 @@ async fn main() -> TardisResult<()> {
         TardisFuns::init_conf(config).await?;
     }
 
-    tauri::build();
+    tauri::build(link);
 
     Ok(())
 }
@@ -87,13 +95,18 @@ async fn check_key(
 ) {
     if target_version == env!("CARGO_PKG_VERSION").to_string() {
         if let Some(check_key_url) = check_key_url {
+            let processor_config = TardisFuns::cs_config::<ProcessorConfig>(DOMAIN_CODE);
+            if let Err(e) = host_policy::check(&check_key_url, &processor_config.allowed_hosts) {
+                log::error!("check key skipped:{e}");
+                return;
+            }
             if let Some(check_key) = check_key {
-                let _ = TardisFuns::web_client()
-                    .post_str_to_str(
-                        format!("{}?check_key={}", check_key_url, check_key),
-                        "",
-                        HashMap::new(),
-                    )
+                let Ok(client) = host_policy::client(processor_config.allowed_hosts.clone()) else {
+                    return;
+                };
+                let _ = client
+                    .post(format!("{}?check_key={}", check_key_url, check_key))
+                    .send()
                     .await;
             }
         }
@@ -105,6 +118,11 @@ async fn check_key(
 pub struct FileProcessParams {
     pub title: String,
     pub upload: Option<FileUploadProcessParams>,
+    // unix timestamps in seconds, the link is refused once expired
+    pub issued_at: Option<i64>,
+    pub expires_at: Option<i64>,
+    // the link is refused if its nonce was already used
+    pub nonce: Option<String>,
 }
 
 #[cfg_attr(test, derive(Eq, PartialEq))]
@@ -122,4 +140,32 @@ pub struct FileUploadProcessParams {
     pub upload_fixed_metadata: Option<HashMap<String, Value>>,
     // fixed upload headers
     pub upload_fixed_headers: Option<HashMap<String, String>>,
+    // multipart upload for large files, single put if absent
+    pub upload_multipart: Option<uploader::MultipartUploadParams>,
+    // must be post, called with the metadata body for files cancelled, skipped or failed after their
+    // metadata is uploaded and before their data is stored
+    pub upload_abort_url: Option<String>,
+    // glob patterns selecting the files found in picked folders, all files if absent,
+    // a pattern without `/` matches the name, otherwise the path inside the picked folder
+    // (`tmp/**` for `<picked>/tmp/**`)
+    pub upload_include: Option<Vec<String>>,
+    // glob patterns of files and folders left out of picked folders, added to the configured ones
+    pub upload_exclude: Option<Vec<String>>,
+    // honour `.gitignore`/`.ignore` files found in picked folders, the configured default if absent
+    pub upload_use_ignore_files: Option<bool>,
+    // the configured default if absent
+    pub upload_symlink: Option<processor_config::SymlinkPolicy>,
+    // which guess of the mime type wins, the configured default if absent
+    pub upload_mime_source: Option<processor_config::MimeSource>,
+    // rules checked before the upload starts, the whole batch is scanned first if present,
+    // the per-file rules are checked again as each file is uploaded
+    pub upload_validation: Option<validation::UploadValidation>,
+    // content hashes sent with the metadata or once the data is sent, not computed if absent,
+    // see `uploader::UploadHashParams`
+    pub upload_hash: Option<uploader::UploadHashParams>,
+    // must be post, asked with the sha256 and size of each file before its upload,
+    // see `uploader::UploadDedupReq`; sha256 is computed even if `upload_hash` is absent
+    pub upload_dedup_url: Option<String>,
+    // check uploads against the storage's checksums, sha256 and md5 are computed for it
+    pub upload_verify: Option<uploader::UploadVerifyParams>,
 }
@@ -0,0 +1,195 @@
+// Text forms of local paths, which may not be valid unicode.
+//
+// The escaped form is lossless: valid unicode is kept as is except `%`, which becomes `%25`,
+// invalid bytes (unix) become `%XX` and unpaired surrogates (windows) become `%uXXXX`.
+use serde::{Deserialize, Deserializer, Serializer};
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+#[cfg(not(target_os = "windows"))]
+pub fn escape(s: &OsStr) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    let mut escaped = String::new();
+    for chunk in s.as_bytes().utf8_chunks() {
+        push_escaped(chunk.valid(), &mut escaped);
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    escaped
+}
+
+#[cfg(target_os = "windows")]
+pub fn escape(s: &OsStr) -> String {
+    use std::os::windows::ffi::OsStrExt;
+    let mut escaped = String::new();
+    for c in char::decode_utf16(s.encode_wide()) {
+        match c {
+            Ok(c) => push_escaped(c.encode_utf8(&mut [0; 4]), &mut escaped),
+            Err(e) => escaped.push_str(&format!("%u{:04X}", e.unpaired_surrogate())),
+        }
+    }
+    escaped
+}
+
+fn push_escaped(valid: &str, escaped: &mut String) {
+    for c in valid.chars() {
+        if c == '%' {
+            escaped.push_str("%25");
+        } else {
+            escaped.push(c);
+        }
+    }
+}
+
+/// Reverse of [`escape`], none if `s` is not a valid escaped form.
+#[cfg(not(target_os = "windows"))]
+pub fn unescape(s: &str) -> Option<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '%' {
+            bytes.push(parse_hex(&mut chars, 2)? as u8);
+        } else {
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+    }
+    Some(OsString::from_vec(bytes))
+}
+
+#[cfg(target_os = "windows")]
+pub fn unescape(s: &str) -> Option<OsString> {
+    use std::os::windows::ffi::OsStringExt;
+    let mut wide = Vec::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '%' {
+            if chars.next_if_eq(&'u').is_some() {
+                wide.push(parse_hex(&mut chars, 4)? as u16);
+            } else {
+                wide.push(parse_hex(&mut chars, 2)? as u16);
+            }
+        } else {
+            wide.extend_from_slice(c.encode_utf16(&mut [0; 2]));
+        }
+    }
+    Some(OsString::from_wide(&wide))
+}
+
+fn parse_hex(chars: &mut impl Iterator<Item = char>, len: usize) -> Option<u32> {
+    let hex = chars.take(len).collect::<String>();
+    if hex.len() != len {
+        return None;
+    }
+    u32::from_str_radix(&hex, 16).ok()
+}
+
+/// The escaped form, none if `s` is valid unicode and needs no escaping.
+pub fn raw(s: &OsStr) -> Option<String> {
+    if s.to_str().is_some() {
+        None
+    } else {
+        Some(escape(s))
+    }
+}
+
+/// Serde of a path as its escaped form, for data kept locally.
+pub mod lossless {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&escape(path.as_os_str()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        let escaped = String::deserialize(deserializer)?;
+        unescape(&escaped)
+            .map(PathBuf::from)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid escaped path {escaped}")))
+    }
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            path: &Option<PathBuf>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match path {
+                Some(path) => super::serialize(path, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<PathBuf>, D::Error> {
+            Option::<String>::deserialize(deserializer)?
+                .map(|escaped| {
+                    unescape(&escaped).map(PathBuf::from).ok_or_else(|| {
+                        serde::de::Error::custom(format!("invalid escaped path {escaped}"))
+                    })
+                })
+                .transpose()
+        }
+    }
+}
+
+/// Serde of a path as its lossy display form, for data sent to the web page.
+pub mod lossy {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&path.to_string_lossy())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Ok(PathBuf::from(String::deserialize(deserializer)?))
+    }
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            path: &Option<PathBuf>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match path {
+                Some(path) => super::serialize(path, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<PathBuf>, D::Error> {
+            Ok(Option::<String>::deserialize(deserializer)?.map(PathBuf::from))
+        }
+    }
+}
+
+#[test]
+fn test_path_codec() {
+    let path = OsStr::new("目录/100%.txt");
+    assert_eq!(escape(path), "目录/100%25.txt");
+    assert_eq!(unescape(&escape(path)).as_deref(), Some(path));
+    assert_eq!(raw(path), None);
+    assert_eq!(unescape("a%2"), None);
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let path = OsStr::from_bytes(b"a\xff\xfeb");
+        assert_eq!(escape(path), "a%FF%FEb");
+        assert_eq!(unescape("a%FF%FEb").as_deref(), Some(path));
+        assert_eq!(raw(path).as_deref(), Some("a%FF%FEb"));
+    }
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::ffi::OsStringExt;
+        let path = OsString::from_wide(&[0x61, 0xD800, 0x62]);
+        assert_eq!(escape(&path), "a%uD800b");
+        assert_eq!(unescape("a%uD800b"), Some(path));
+    }
+}
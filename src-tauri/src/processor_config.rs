@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Duration};
+use tardis::rand::random;
 
 pub const DOMAIN_CODE: &str = "processor";
 
@@ -7,10 +8,171 @@ pub const DOMAIN_CODE: &str = "processor";
 #[serde(default)]
 pub struct ProcessorConfig {
     pub concurrent: usize,
+    pub retry: RetryConfig,
+    pub filter: FilterConfig,
+    pub mime_source: MimeSource,
+    pub signature: SignatureConfig,
+    // hosts or url prefixes the upload params, the server responses and their redirects may point to,
+    // see `host_policy`. Empty allows none, it must be set by the deployment
+    pub allowed_hosts: Vec<String>,
+    // hosts or url prefixes the params of ticket links may be fetched from, see `link_ticket`.
+    // Empty disables ticket links
+    pub ticket_endpoints: Vec<String>,
 }
 
 impl Default for ProcessorConfig {
     fn default() -> Self {
-        ProcessorConfig { concurrent: 5 }
+        ProcessorConfig {
+            concurrent: 5,
+            retry: RetryConfig::default(),
+            filter: FilterConfig::default(),
+            mime_source: MimeSource::default(),
+            signature: SignatureConfig::default(),
+            allowed_hosts: vec![],
+            ticket_endpoints: vec![],
+        }
     }
 }
+
+/// Keys the deep link params may be signed with, see `link_signature`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct SignatureConfig {
+    // reject unsigned links
+    pub required: bool,
+    // shared secrets of `hmac-sha256` signatures
+    pub hmac_secrets: Vec<String>,
+    // standard base64 of the 32 bytes public keys of `ed25519` signatures
+    pub ed25519_public_keys: Vec<String>,
+}
+
+impl Default for SignatureConfig {
+    fn default() -> Self {
+        SignatureConfig {
+            required: true,
+            hmac_secrets: vec![],
+            ed25519_public_keys: vec![],
+        }
+    }
+}
+
+/// Which guess of the mime type wins when the content and the extension of a file disagree.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MimeSource {
+    // magic bytes of the first few KB
+    #[default]
+    Content,
+    Extension,
+}
+
+/// Retry policy of the metadata post and the data put of each file.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    // including the first attempt
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    // randomize the delay between half and full of the backoff
+    pub jitter: bool,
+    pub retryable_status_codes: Vec<u16>,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 3,
+            base_delay_ms: 500,
+            max_delay_ms: 10_000,
+            jitter: true,
+            retryable_status_codes: vec![408, 429, 500, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn is_retryable_status(&self, status: u16) -> bool {
+        self.retryable_status_codes.contains(&status)
+    }
+
+    /// Delay before the next attempt, `attempt` is the one that just failed (starting from 1).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(20);
+        let delay = self
+            .base_delay_ms
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay_ms);
+        let delay = if self.jitter {
+            delay / 2 + (random::<f64>() * (delay - delay / 2) as f64) as u64
+        } else {
+            delay
+        };
+        Duration::from_millis(delay)
+    }
+}
+
+/// User-level filters of folder uploads, the ones of the upload params are added to them.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct FilterConfig {
+    // glob patterns, a pattern without `/` matches the name, otherwise the path inside the picked folder
+    pub exclude: Vec<String>,
+    pub use_ignore_files: bool,
+    pub symlink: SymlinkPolicy,
+}
+
+/// How symbolic links (and junctions on Windows) found by a scan are handled.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    // upload the link target, links to a directory being scanned are skipped
+    #[default]
+    Follow,
+    // upload only the metadata of the link with its target
+    Link,
+    Skip,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            exclude: [
+                ".git",
+                ".svn",
+                "node_modules",
+                ".DS_Store",
+                "Thumbs.db",
+                "desktop.ini",
+                "*.swp",
+                "*.swo",
+                "*~",
+            ]
+            .iter()
+            .map(|pattern| pattern.to_string())
+            .collect(),
+            use_ignore_files: false,
+            symlink: SymlinkPolicy::default(),
+        }
+    }
+}
+
+#[test]
+fn test_retry_backoff() {
+    let retry = RetryConfig {
+        jitter: false,
+        ..Default::default()
+    };
+    assert_eq!(retry.backoff(1), Duration::from_millis(500));
+    assert_eq!(retry.backoff(2), Duration::from_millis(1000));
+    assert_eq!(retry.backoff(3), Duration::from_millis(2000));
+    assert_eq!(retry.backoff(10), Duration::from_millis(10_000));
+    assert_eq!(retry.backoff(u32::MAX), Duration::from_millis(10_000));
+
+    let retry = RetryConfig::default();
+    for attempt in 1..10 {
+        let delay = retry.backoff(attempt);
+        assert!(delay <= Duration::from_millis(10_000));
+        assert!(delay >= Duration::from_millis(250));
+    }
+    assert!(retry.is_retryable_status(502));
+    assert!(!retry.is_retryable_status(403));
+}
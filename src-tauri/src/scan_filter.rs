@@ -0,0 +1,237 @@
+use globset::{Glob, GlobMatcher};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tardis::{
+    basic::{error::TardisError, result::TardisResult},
+    tokio::fs,
+};
+
+use crate::{
+    processor_config::{FilterConfig, SymlinkPolicy},
+    FileUploadProcessParams,
+};
+
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UploadExcludeReason {
+    // matches an exclude pattern
+    Exclude,
+    // a file matching none of the include patterns
+    NotIncluded,
+    // ignored by a `.gitignore`/`.ignore` file
+    IgnoreFile,
+    // a link skipped by the symlink policy
+    Symlink,
+    // a followed link to a directory being scanned
+    SymlinkCycle,
+    // fifo, socket or device
+    SpecialFile,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UploadExcludedEntry {
+    #[serde(with = "crate::path_codec::lossy")]
+    pub relative_path: PathBuf,
+    pub is_dir: bool,
+    pub reason: UploadExcludeReason,
+    // the matching pattern, prefixed by its ignore file for `IgnoreFile`, the link target for `SymlinkCycle`
+    pub rule: String,
+}
+
+struct Pattern {
+    pattern: String,
+    // matches the name instead of the path inside the picked folder
+    by_name: bool,
+    matcher: GlobMatcher,
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> TardisResult<Self> {
+        let matcher = Glob::new(pattern)
+            .map_err(|e| {
+                TardisError::bad_request(
+                    &format!("invalid pattern {pattern}:{e}"),
+                    "400-upload-invalid-pattern",
+                )
+            })?
+            .compile_matcher();
+        Ok(Pattern {
+            pattern: pattern.to_string(),
+            by_name: !pattern.contains('/'),
+            matcher,
+        })
+    }
+
+    fn is_match(&self, folder_path: &Path) -> bool {
+        if self.by_name {
+            folder_path
+                .file_name()
+                .is_some_and(|name| self.matcher.is_match(name))
+        } else {
+            self.matcher.is_match(folder_path)
+        }
+    }
+}
+
+/// Include/exclude rules of the entries found in picked folders, the picked ones are always kept.
+pub struct ScanFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    use_ignore_files: bool,
+    symlink: SymlinkPolicy,
+    // ignore files of the directories being scanned, innermost last
+    ignores: Vec<Option<Gitignore>>,
+}
+
+impl ScanFilter {
+    pub fn new(params: &FileUploadProcessParams, config: &FilterConfig) -> TardisResult<Self> {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| Pattern::new(pattern))
+                .collect::<TardisResult<Vec<_>>>()
+        };
+        Ok(ScanFilter {
+            include: compile(params.upload_include.as_deref().unwrap_or_default())?,
+            exclude: compile(
+                &[
+                    config.exclude.as_slice(),
+                    params.upload_exclude.as_deref().unwrap_or_default(),
+                ]
+                .concat(),
+            )?,
+            use_ignore_files: params
+                .upload_use_ignore_files
+                .unwrap_or(config.use_ignore_files),
+            symlink: params.upload_symlink.unwrap_or(config.symlink),
+            ignores: vec![],
+        })
+    }
+
+    pub fn symlink(&self) -> SymlinkPolicy {
+        self.symlink
+    }
+
+    /// Returns why the entry is left out with the matching rule, none if it is kept.
+    /// `folder_path` is relative to the picked folder the entry is found in.
+    pub fn check(
+        &self,
+        path: &Path,
+        folder_path: &Path,
+        is_dir: bool,
+    ) -> Option<(UploadExcludeReason, String)> {
+        if let Some(pattern) = self
+            .exclude
+            .iter()
+            .find(|pattern| pattern.is_match(folder_path))
+        {
+            return Some((UploadExcludeReason::Exclude, pattern.pattern.clone()));
+        }
+        // rules of inner ignore files take precedence
+        for ignore in self.ignores.iter().rev().flatten() {
+            match ignore.matched(path, is_dir) {
+                Match::None => continue,
+                Match::Ignore(glob) => {
+                    let from = glob
+                        .from()
+                        .map(|from| from.display().to_string())
+                        .unwrap_or_default();
+                    return Some((
+                        UploadExcludeReason::IgnoreFile,
+                        format!("{from}:{}", glob.original()),
+                    ));
+                }
+                Match::Whitelist(_) => break,
+            }
+        }
+        if !is_dir
+            && !self.include.is_empty()
+            && !self
+                .include
+                .iter()
+                .any(|pattern| pattern.is_match(folder_path))
+        {
+            return Some((UploadExcludeReason::NotIncluded, String::new()));
+        }
+        None
+    }
+
+    /// Load the ignore files of a directory before its entries are checked.
+    pub async fn enter_dir(&mut self, dir: &Path) {
+        if !self.use_ignore_files {
+            return;
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        let mut found = false;
+        for name in IGNORE_FILES {
+            let ignore_path = dir.join(name);
+            let Ok(content) = fs::read_to_string(&ignore_path).await else {
+                continue;
+            };
+            found = true;
+            for line in content.lines() {
+                if let Err(e) = builder.add_line(Some(ignore_path.clone()), line) {
+                    log::warn!("invalid rule in {ignore_path:?}:{e}");
+                }
+            }
+        }
+        let ignore = if found {
+            builder
+                .build()
+                .map_err(|e| log::warn!("invalid ignore files in {dir:?}:{e}"))
+                .ok()
+        } else {
+            None
+        };
+        self.ignores.push(ignore);
+    }
+
+    pub fn leave_dir(&mut self) {
+        self.ignores.pop();
+    }
+}
+
+#[test]
+fn test_scan_filter() {
+    let filter = ScanFilter {
+        include: vec![Pattern::new("*.jpg").unwrap()],
+        exclude: vec![
+            Pattern::new("node_modules").unwrap(),
+            Pattern::new("tmp/**").unwrap(),
+        ],
+        use_ignore_files: false,
+        symlink: SymlinkPolicy::Follow,
+        ignores: vec![],
+    };
+    // paths inside the picked folder `photos`
+    let check = |folder_path: &str, is_dir: bool| {
+        filter
+            .check(
+                &Path::new("/home/photos").join(folder_path),
+                Path::new(folder_path),
+                is_dir,
+            )
+            .map(|(reason, _)| reason)
+    };
+    assert_eq!(check("a.jpg", false), None);
+    assert_eq!(check("sub", true), None);
+    assert_eq!(
+        check("a/node_modules", true),
+        Some(UploadExcludeReason::Exclude)
+    );
+    assert_eq!(
+        check("tmp/b.jpg", false),
+        Some(UploadExcludeReason::Exclude)
+    );
+    assert_eq!(check("sub/tmp/b.jpg", false), None);
+    assert_eq!(
+        check("a.png", false),
+        Some(UploadExcludeReason::NotIncluded)
+    );
+    assert!(Pattern::new("a[").is_err());
+}
@@ -5,20 +5,25 @@ use std::env;
 #[cfg(test)]
 use crate::FileUploadProcessParams;
 use crate::{
-    uploader::{self, UploadStatsResp, BACKGROUND_TASK},
+    host_policy,
+    journal::{self, ResumableUploadResp},
+    link_payload, link_replay, link_signature, link_ticket,
+    processor_config::{ProcessorConfig, SignatureConfig, DOMAIN_CODE},
+    uploader::{self, UploadStatsResp},
     FileProcessParams, PARAMS,
 };
-use base64::{engine::general_purpose, Engine as _};
 use log::{error, info};
+#[cfg(not(debug_assertions))]
+use tardis::config::config_dto::TardisConfig;
+#[cfg(not(debug_assertions))]
+use tardis::futures::executor;
 use tardis::{
     basic::{error::TardisError, result::TardisResult},
     TardisFuns,
 };
 #[cfg(not(debug_assertions))]
-use tardis::{config::config_dto::TardisConfig, futures::executor};
-#[cfg(not(debug_assertions))]
 use tauri::path::BaseDirectory;
-use tauri::{Manager, Window};
+use tauri::{AppHandle, Emitter as _, Manager, Window};
 use tauri_plugin_log::{Target, TargetKind};
 
 #[tauri::command]
@@ -27,6 +32,34 @@ async fn upload_files(files_uris: Vec<String>, window: Window) -> TardisResult<U
     uploader::upload_files(files_uris, window).await
 }
 
+#[tauri::command]
+async fn get_resumable_upload(window: Window) -> TardisResult<Option<ResumableUploadResp>> {
+    match crate::get_params().upload {
+        Some(upload) => journal::get_resumable(&window, &upload).await,
+        None => Ok(None),
+    }
+}
+
+#[tauri::command]
+async fn resume_upload(window: Window) -> TardisResult<UploadStatsResp> {
+    info!("resume_upload");
+    uploader::resume_upload(window).await
+}
+
+#[tauri::command]
+async fn discard_resumable_upload(window: Window) -> TardisResult<()> {
+    match crate::get_params().upload {
+        Some(upload) => journal::discard(&window, &upload).await,
+        None => Ok(()),
+    }
+}
+
+#[tauri::command]
+async fn retry_failed_files(window: Window) -> TardisResult<UploadStatsResp> {
+    info!("retry_failed_files");
+    uploader::retry_failed_files(window).await
+}
+
 #[tauri::command]
 async fn get_params() -> TardisResult<FileProcessParams> {
     Ok((*PARAMS.lock().unwrap()).clone())
@@ -34,14 +67,32 @@ async fn get_params() -> TardisResult<FileProcessParams> {
 
 #[tauri::command]
 async fn cancel() -> TardisResult<()> {
-    let guard = BACKGROUND_TASK
-        .try_lock()
-        .ok_or(TardisError::io_error(&format!("try lock error"), "error"))?;
-    if let Some(task) = &(*guard) {
-        task.abort();
-        return Ok(());
-    }
-    Ok(())
+    info!("cancel");
+    uploader::cancel().await
+}
+
+#[tauri::command]
+async fn cancel_scan() -> TardisResult<()> {
+    info!("cancel scan");
+    uploader::cancel_scan().await
+}
+
+#[tauri::command]
+async fn skip_file(id: String) -> TardisResult<()> {
+    info!("skip file:{id}");
+    uploader::skip_file(id).await
+}
+
+#[tauri::command]
+async fn pause() -> TardisResult<()> {
+    info!("pause");
+    uploader::pause().await
+}
+
+#[tauri::command]
+async fn resume() -> TardisResult<()> {
+    info!("resume");
+    uploader::resume().await
 }
 
 #[tauri::command]
@@ -55,9 +106,9 @@ fn set_params(params: FileProcessParams) -> TardisResult<()> {
     Ok(())
 }
 
-pub fn build() {
+pub fn build(link: Option<String>) {
     tauri::Builder::default()
-        .setup(|app| {
+        .setup(move |app| {
             #[cfg(not(debug_assertions))]
             {
                 let config_path = app
@@ -75,6 +126,11 @@ pub fn build() {
                         .expect("can't init config");
                 });
             }
+            if let Some(link) = link.clone() {
+                // the params may be fetched, the page is told once they are set
+                let app = app.handle().clone();
+                tauri::async_runtime::spawn(async move { load_params(&app, &link).await });
+            }
             let window = app.get_webview_window("main").unwrap();
             let current_monitor = window.current_monitor().unwrap().unwrap();
             let screen_size = current_monitor.size();
@@ -104,8 +160,16 @@ pub fn build() {
         .plugin(tauri_plugin_dialog::init())
         .invoke_handler(tauri::generate_handler![
             upload_files,
+            get_resumable_upload,
+            resume_upload,
+            discard_resumable_upload,
+            retry_failed_files,
             get_params,
             cancel,
+            cancel_scan,
+            skip_file,
+            pause,
+            resume,
             get_version
         ])
         .build(tauri::generate_context!())
@@ -113,48 +177,121 @@ pub fn build() {
         .run(|_app, _event| {
             #[cfg(any(target_os = "macos", target_os = "ios"))]
             if let tauri::RunEvent::Opened { urls } = _event {
-                if let Some(url) = urls.get(0) {
-                    let params = parse_params(url);
-                    info!("Opened url parse to params: {:?}", params);
-                    let _ = set_params(params);
+                if let Some(url) = urls.first() {
+                    let app = _app.clone();
+                    let link = url.to_string();
+                    tauri::async_runtime::spawn(async move { load_params(&app, &link).await });
                 }
             }
         });
 }
 
-pub fn parse_params(url: &reqwest::Url) -> FileProcessParams {
-    match url.host_str() {
-        Some(url_host) => match general_purpose::URL_SAFE.decode(url_host) {
-            Ok(base64) => match String::from_utf8(base64) {
-                Ok(base64_str) => {
-                    match TardisFuns::json.str_to_obj::<FileProcessParams>(&base64_str) {
-                        Ok(params) => params,
-                        Err(e) => {
-                            error!("json fail:{e}");
-                            panic!("json fail:{e}")
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("base64 decode to String fail:{e}");
-                    panic!("base64 decode to String fail:{e}")
-                }
-            },
-            Err(e) => {
-                error!("base64 decode fail:{e}");
-                panic!("base64 decode fail:{e}")
-            }
-        },
-        None => {
-            error!("url not have host!");
-            panic!("url not have host!")
+/// Verify and apply the params of a deep link, needs the config to be loaded.
+/// A rejected link opens no upload and tells the user why. The params are emitted as `params`.
+pub async fn load_params(app: &AppHandle, link: &str) {
+    let config = TardisFuns::cs_config::<ProcessorConfig>(DOMAIN_CODE);
+    let params = match reqwest::Url::parse(link).map_err(|e| {
+        TardisError::bad_request(&format!("parse url fail:{e}"), "400-params-invalid-url")
+    }) {
+        Ok(url) => read_params(app, &url, &config).await,
+        Err(e) => Err(e),
+    };
+    match params {
+        Ok(params) => {
+            info!("params: {:?}", params);
+            crate::check_key(
+                params
+                    .upload
+                    .clone()
+                    .map(|p| p.target_kind_key)
+                    .unwrap_or_default(),
+                params.upload.clone().and_then(|p| p.check_key_url),
+                params.upload.clone().and_then(|p| p.check_key),
+            )
+            .await;
+            let _ = set_params(params);
+        }
+        Err(e) => {
+            error!("reject link params:{e}");
+            let _ = set_params(FileProcessParams {
+                title: format!("链接校验失败，已拒绝上传：{}", e.message),
+                upload: None,
+                issued_at: None,
+                expires_at: None,
+                nonce: None,
+            });
         }
     }
+    let _ = app.emit("params", crate::get_params());
+}
+
+// The params carried by the link or fetched with its ticket, verified
+async fn read_params(
+    app: &AppHandle,
+    url: &reqwest::Url,
+    config: &ProcessorConfig,
+) -> TardisResult<FileProcessParams> {
+    // a misconfigured deployment rejects every link, the app itself still starts
+    link_signature::check_config(&config.signature)?;
+    let params = match link_ticket::ticket(url)? {
+        Some(ticket) => {
+            let payload = link_ticket::fetch(&ticket, &config.ticket_endpoints).await?;
+            verify_params(payload, url, &config.signature)?
+        }
+        None => parse_params(url, &config.signature)?,
+    };
+    // refused before the nonce is consumed, so the link can be opened again once the hosts are fixed
+    if let Some(upload) = &params.upload {
+        host_policy::check_params(upload, &config.allowed_hosts)?;
+    }
+    check_replay(app, params).await
+}
+
+async fn check_replay(
+    app: &AppHandle,
+    params: FileProcessParams,
+) -> TardisResult<FileProcessParams> {
+    let now = link_replay::now();
+    link_replay::check_expiry(&params, now)?;
+    let app_data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| TardisError::io_error(&format!("app data dir error:{e}"), "error"))?;
+    link_replay::consume_nonce(&app_data_dir, &params, now).await?;
+    Ok(params)
+}
+
+pub fn parse_params(
+    url: &reqwest::Url,
+    signature: &SignatureConfig,
+) -> TardisResult<FileProcessParams> {
+    let payload = link_payload::extract(url)?;
+    verify_params(payload, url, signature)
+}
+
+// `payload` is signed in the query of `url`
+fn verify_params(
+    payload: Vec<u8>,
+    url: &reqwest::Url,
+    signature: &SignatureConfig,
+) -> TardisResult<FileProcessParams> {
+    link_signature::verify(&payload, url, signature)?;
+    let payload = String::from_utf8(payload).map_err(|e| {
+        TardisError::bad_request(
+            &format!("params to String fail:{e}"),
+            "400-params-invalid-base64",
+        )
+    })?;
+    TardisFuns::json.str_to_obj::<FileProcessParams>(&payload)
 }
 
 #[test]
 fn test_parse_params() {
     let mut upload_fixed_headers = HashMap::new();
     upload_fixed_headers.insert(String::from("Token"), String::from("78hhySDFGT56gGh65"));
-    assert_eq!(parse_params(&reqwest::Url::parse("file-processor://eyJ0aXRsZSI6IuS4iuS8oOWIsO-8mmtub3dsZWRnZS03NC8iLCJ1cGxvYWQiOnsidGFyZ2V0X2tpbmRfa2V5IjoiIiwidGFyZ2V0X29ial9rZXkiOiIiLCJvdmVyd3JpdGUiOnRydWUsInVwbG9hZF9tZXRhZGF0YV91cmwiOiJ4eHh4IiwidXBsb2FkX2ZpeGVkX2hlYWRlcnMiOnsiVG9rZW4iOiI3OGhoeVNERkdUNTZnR2g2NSJ9fX0=").unwrap()),FileProcessParams{ title: String::from("上传到：knowledge-74/"), upload: Some(FileUploadProcessParams{target_kind_key:String::new(),target_obj_key:String::new(),check_key:None,upload_metadata_url:String::from("xxxx"),upload_metadata_rename_filed:None,upload_fixed_metadata:None,upload_fixed_headers:Some(upload_fixed_headers),check_key_url:None, target_version:env!("CARGO_PKG_VERSION").to_string() }) })
+    let unsigned = SignatureConfig {
+        required: false,
+        ..Default::default()
+    };
+    assert_eq!(parse_params(&reqwest::Url::parse("file-processor://eyJ0aXRsZSI6IuS4iuS8oOWIsO-8mmtub3dsZWRnZS03NC8iLCJ1cGxvYWQiOnsidGFyZ2V0X2tpbmRfa2V5IjoiIiwidGFyZ2V0X29ial9rZXkiOiIiLCJvdmVyd3JpdGUiOnRydWUsInVwbG9hZF9tZXRhZGF0YV91cmwiOiJ4eHh4IiwidXBsb2FkX2ZpeGVkX2hlYWRlcnMiOnsiVG9rZW4iOiI3OGhoeVNERkdUNTZnR2g2NSJ9fX0=").unwrap(), &unsigned).unwrap(),FileProcessParams{ title: String::from("上传到：knowledge-74/"), upload: Some(FileUploadProcessParams{target_kind_key:String::new(),target_obj_key:String::new(),check_key:None,upload_metadata_url:String::from("xxxx"),upload_metadata_rename_filed:None,upload_fixed_metadata:None,upload_fixed_headers:Some(upload_fixed_headers),upload_multipart:None,upload_abort_url:None,upload_include:None,upload_exclude:None,upload_use_ignore_files:None,upload_symlink:None,upload_mime_source:None,upload_validation:None,upload_hash:None,upload_dedup_url:None,upload_verify:None,check_key_url:None, target_version:env!("CARGO_PKG_VERSION").to_string() }), issued_at:None, expires_at:None, nonce:None })
 }
@@ -1,51 +1,273 @@
-use log::info;
+use base64::{engine::general_purpose, Engine as _};
+use log::{error, info};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-#[cfg(any(target_os = "macos", target_os = "linux"))]
-use std::os::unix::fs::MetadataExt;
-#[cfg(target_os = "windows")]
-use std::os::windows::fs::MetadataExt;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    future::Future,
+    io::SeekFrom,
     path::{Path, PathBuf},
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tardis::{
     basic::{error::TardisError, result::TardisResult},
-    futures::{lock::Mutex, stream, StreamExt as _},
+    crypto::crypto_digest::algorithm::{Digest as _, Sha256},
+    futures::{
+        lock::{Mutex, MutexGuard},
+        stream, StreamExt as _,
+    },
     rand::random,
     tokio::{
-        fs::{read_dir, File},
-        io::AsyncReadExt,
+        fs::{self, read_dir, File},
+        io::{AsyncRead, AsyncReadExt as _, AsyncSeekExt as _},
         spawn,
-        sync::{mpsc, Semaphore},
+        sync::{mpsc, oneshot, watch, OwnedSemaphorePermit, Semaphore},
     },
     web::reqwest,
     TardisFuns,
 };
 use tauri::{async_runtime::TokioJoinHandle, Emitter as _, Window};
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
 
-use crate::{processor_config::{ProcessorConfig, DOMAIN_CODE}, FileUploadProcessParams};
+use crate::{
+    content_hash::{self, ContentHasher, SharedHasher},
+    host_policy, integrity,
+    journal::{UploadFileState, UploadJournal},
+    path_codec,
+    processor_config::{MimeSource, ProcessorConfig, RetryConfig, SymlinkPolicy, DOMAIN_CODE},
+    scan_filter::{ScanFilter, UploadExcludeReason, UploadExcludedEntry},
+    validation::{UploadValidation, UploadViolation, Validator},
+    FileUploadProcessParams,
+};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct UploadProgressResp {
+    pub total_file_numbers: usize,
+    pub total_file_size: u64,
+    // files are still being found, the totals may grow
+    pub scanning: bool,
     pub uploaded_file_numbers: usize,
     pub uploaded_file_size: u64,
+    pub paused: bool,
     pub current_files: Vec<UploadFileInfo>,
+    pub current_parts: Vec<UploadPartProgress>,
+    pub current_retries: Vec<UploadRetryProgress>,
     pub success_files: Vec<UploadFileInfo>,
-    pub fail_files: Vec<UploadFileInfo>,
+    pub fail_files: Vec<UploadFailFileInfo>,
+    pub cancelled_files: Vec<UploadFileInfo>,
+    pub skipped_files: Vec<UploadFileInfo>,
+    // content already on the server, only the metadata is registered
+    pub deduplicated_files: Vec<UploadFileInfo>,
+    // bytes not sent thanks to deduplication so far
+    pub deduplicated_file_size: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UploadFileInfo {
     pub id: String,
+    // lossy if the name is not valid unicode
     pub name: String,
-    // Relative path
+    // Relative path, sent to the web page lossy
+    #[serde(with = "path_codec::lossy")]
     pub relative_path: PathBuf,
     pub size: u64,
     pub mime_type: String,
+    // guesses `mime_type` is chosen from, for regular files, the content is sniffed when uploaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type_by_content: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type_by_extension: Option<String>,
+    // hex digests of the content, computed before the metadata post if the params ask for them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+    // target of a link uploaded as a link
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "path_codec::lossy::option"
+    )]
+    pub link_target: Option<PathBuf>,
+    // Local absolute path, never sent to the web page
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+// Emitted as `upload-summary` once the batch is finished, including the files of the batch it retries
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UploadSummaryResp {
+    pub total_file_numbers: usize,
+    pub cancelled: bool,
+    pub success_files: Vec<UploadFileInfo>,
+    pub fail_files: Vec<UploadFailFileInfo>,
+    pub cancelled_files: Vec<UploadFileInfo>,
+    pub skipped_files: Vec<UploadFileInfo>,
+    pub deduplicated_files: Vec<UploadFileInfo>,
+    pub deduplicated_file_size: u64,
+}
+
+// Emitted as `upload-scan` while the picked files and folders are scanned
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct UploadScanResp {
+    pub found_file_numbers: usize,
+    pub found_dir_numbers: usize,
+    pub found_file_size: u64,
+    // left out by the filters, the content of excluded directories is not scanned
+    pub excluded_file_numbers: usize,
+    pub excluded_dir_numbers: usize,
+    // entries excluded since the previous event
+    pub excluded: Vec<UploadExcludedEntry>,
+    // relative path of the directory being read
+    #[serde(with = "path_codec::lossy")]
+    pub current_dir: PathBuf,
+    pub finished: bool,
+    // stopped by the user, only the files found so far are uploaded
+    pub cancelled: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UploadFailFileInfo {
+    #[serde(flatten)]
+    pub file: UploadFileInfo,
+    pub failure: UploadFailure,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UploadFailStage {
+    // open or read the local file
+    ReadFile,
+    // post to `upload_metadata_url` or `upload_hash.digest_url`
+    Metadata,
+    // the upload url (or multipart upload init) returned by the metadata response is unusable
+    UploadUrl,
+    // put the data
+    Upload,
+    // complete the multipart upload
+    Complete,
+    // the stored object doesn't match the local file
+    Verify,
+    // the file breaks a rule of `upload_validation`, e.g. it changed since the batch was validated
+    Validation,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UploadFailure {
+    pub stage: UploadFailStage,
+    // http status of the failed request, none if no response
+    pub status: Option<u16>,
+    pub message: String,
+    // whether uploading the file again may succeed
+    pub retryable: bool,
+}
+
+const MAX_FAILURE_MESSAGE_LEN: usize = 512;
+
+impl UploadFailure {
+    fn new(stage: UploadFailStage, message: impl Into<String>) -> Self {
+        let message: String = message.into();
+        UploadFailure {
+            stage,
+            status: None,
+            message: message.chars().take(MAX_FAILURE_MESSAGE_LEN).collect(),
+            retryable: false,
+        }
+    }
+
+    // The request didn't get a response, e.g. connection reset or timeout
+    fn network(stage: UploadFailStage, message: impl Into<String>) -> Self {
+        UploadFailure {
+            retryable: true,
+            ..UploadFailure::new(stage, message)
+        }
+    }
+
+    fn status(
+        stage: UploadFailStage,
+        status: u16,
+        message: impl Into<String>,
+        retry: &RetryConfig,
+    ) -> Self {
+        UploadFailure {
+            status: Some(status),
+            retryable: retry.is_retryable_status(status),
+            ..UploadFailure::new(stage, message)
+        }
+    }
+
+    async fn from_response(
+        stage: UploadFailStage,
+        response: reqwest::Response,
+        retry: &RetryConfig,
+    ) -> Self {
+        let status = response.status().as_u16();
+        let body = response.text().await.unwrap_or_default();
+        UploadFailure::status(stage, status, body, retry)
+    }
+}
+
+impl std::fmt::Display for UploadFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "{:?} status {status}: {}", self.stage, self.message),
+            None => write!(f, "{:?}: {}", self.stage, self.message),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UploadPartProgress {
+    pub file_id: String,
+    pub part_number: usize,
+    pub uploaded_parts: usize,
+    pub total_parts: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UploadRetryProgress {
+    pub file_id: String,
+    // the attempt about to start, starting from 1
+    pub attempt: u32,
+    pub max_attempts: u32,
+    pub reason: String,
+}
+
+enum UploadEvent {
+    // next file taken from the queue with its upload slot, none once the queue is finished
+    Next(Option<(OwnedSemaphorePermit, UploadFileInfo)>),
+    Start(UploadFileInfo),
+    Part(UploadPartProgress),
+    Retry(UploadRetryProgress),
+    Paused(bool),
+    Done(UploadFileInfo, UploadOutcome),
+}
+
+enum UploadOutcome {
+    Success,
+    Fail(UploadFailure),
+    Cancelled,
+    // skipped by the user, the rest of the batch goes on
+    Skipped,
+    // the server already has the content
+    Deduplicated,
+}
+
+// Body of the post to `upload_dedup_url`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UploadDedupReq {
+    pub sha256: String,
+    pub size: u64,
+    // the metadata body, to be registered by the server if it has the content
+    pub metadata: Value,
+}
+
+// Response of `upload_dedup_url`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct UploadDedupResp {
+    // the content exists and the metadata is registered, the data is not sent
+    exists: bool,
 }
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub enum UploadFileInfoFiled {
@@ -53,6 +275,16 @@ pub enum UploadFileInfoFiled {
     RelativePath,
     Size,
     MimeType,
+    MimeTypeByContent,
+    MimeTypeByExtension,
+    Sha256,
+    Md5,
+    // escaped forms of the name and the relative path, only sent if they are not valid unicode
+    NameRaw,
+    RelativePathRaw,
+    // target of a link uploaded as a link, its escaped form only if it is not valid unicode
+    LinkTarget,
+    LinkTargetRaw,
 }
 impl UploadFileInfoFiled {
     fn get_all() -> Vec<UploadFileInfoFiled> {
@@ -61,63 +293,220 @@ impl UploadFileInfoFiled {
             UploadFileInfoFiled::RelativePath,
             UploadFileInfoFiled::Size,
             UploadFileInfoFiled::MimeType,
+            UploadFileInfoFiled::MimeTypeByContent,
+            UploadFileInfoFiled::MimeTypeByExtension,
+            UploadFileInfoFiled::Sha256,
+            UploadFileInfoFiled::Md5,
+            UploadFileInfoFiled::NameRaw,
+            UploadFileInfoFiled::RelativePathRaw,
+            UploadFileInfoFiled::LinkTarget,
+            UploadFileInfoFiled::LinkTargetRaw,
         ]
     }
+    // Key of the filed in the bodies, renamed by `upload_metadata_rename_filed`
+    fn name(&self, config: &FileUploadProcessParams) -> String {
+        config
+            .upload_metadata_rename_filed
+            .as_ref()
+            .and_then(|map_filed| map_filed.get(self))
+            .cloned()
+            .unwrap_or_else(|| self.to_str_filed().to_string())
+    }
     fn to_str_filed(&self) -> &str {
         match self {
             UploadFileInfoFiled::Name => "name",
             UploadFileInfoFiled::RelativePath => "relative_path",
             UploadFileInfoFiled::Size => "size",
             UploadFileInfoFiled::MimeType => "mime_type",
+            UploadFileInfoFiled::MimeTypeByContent => "mime_type_by_content",
+            UploadFileInfoFiled::MimeTypeByExtension => "mime_type_by_extension",
+            UploadFileInfoFiled::Sha256 => "sha256",
+            UploadFileInfoFiled::Md5 => "md5",
+            UploadFileInfoFiled::NameRaw => "name_raw",
+            UploadFileInfoFiled::RelativePathRaw => "relative_path_raw",
+            UploadFileInfoFiled::LinkTarget => "link_target",
+            UploadFileInfoFiled::LinkTargetRaw => "link_target_raw",
         }
     }
 }
 
 pub type UploadMapFiled = HashMap<UploadFileInfoFiled, String>;
 
+#[cfg_attr(test, derive(Eq, PartialEq))]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MultipartUploadParams {
+    // files larger than this size(bytes) are uploaded in parts
+    pub threshold: u64,
+    pub part_size: u64,
+    // concurrent parts of one file
+    #[serde(default = "default_multipart_concurrent")]
+    pub concurrent: usize,
+}
+
+#[cfg_attr(test, derive(Eq, PartialEq))]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UploadHashParams {
+    // Without it, each file is read once more to be hashed before its metadata post, whose body
+    // carries the digests as `sha256`/`md5`, renamed by `upload_metadata_rename_filed`.
+    // With it, the digests are computed as the data is sent in a single read, then posted to it
+    // (must be post) as `sha256`, `md5` and `size`, renamed likewise, with the metadata body as `metadata`
+    #[serde(default)]
+    pub digest_url: Option<String>,
+    // sha256 is always computed, md5 only if asked
+    #[serde(default)]
+    pub md5: bool,
+    // send the md5 as `Content-MD5` of single puts, implies `md5`.
+    // The file is then hashed before its upload, and the metadata carries the digests
+    #[serde(default)]
+    pub content_md5: bool,
+}
+
+impl UploadHashParams {
+    // The metadata post needs the digests
+    fn before_upload(&self) -> bool {
+        self.content_md5 || self.digest_url.is_none()
+    }
+}
+
+#[cfg_attr(test, derive(Eq, PartialEq))]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UploadVerifyParams {
+    // head the stored object instead of checking the headers of the put or complete response,
+    // at the `head_url` returned with the upload url or in the multipart init
+    #[serde(default)]
+    pub head: bool,
+}
+
+fn default_multipart_concurrent() -> usize {
+    3
+}
+
+impl MultipartUploadParams {
+    fn is_applicable(&self, size: u64) -> bool {
+        self.part_size > 0 && size > self.threshold
+    }
+    fn part_count(&self, size: u64) -> u64 {
+        size.div_ceil(self.part_size)
+    }
+}
+
+// Response of `upload_metadata_url` when the server accepts a multipart upload,
+// otherwise the response is the single upload url.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct MultipartUploadInit {
+    upload_id: String,
+    part_urls: Vec<String>,
+    // must be post
+    complete_url: String,
+    // must be post, called if the upload is cancelled or fails, `upload_abort_url` of the params is used if absent
+    abort_url: Option<String>,
+    // the completed object, headed by `upload_verify`, whose checks only read the headers of the
+    // complete response if absent
+    #[serde(default)]
+    head_url: Option<String>,
+}
+
+// Response of `upload_metadata_url` for a single put whose object can be headed,
+// the single upload url alone is accepted as well.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct SingleUploadInit {
+    upload_url: String,
+    // the stored object, headed by `upload_verify`, whose checks only read the headers of the
+    // put response if absent
+    #[serde(default)]
+    head_url: Option<String>,
+}
+
+impl SingleUploadInit {
+    fn from_response(upload_result: String) -> Self {
+        TardisFuns::json
+            .str_to_obj::<SingleUploadInit>(&upload_result)
+            .unwrap_or(SingleUploadInit {
+                upload_url: upload_result,
+                head_url: None,
+            })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct MultipartUploadPart {
+    part_number: usize,
+    etag: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct MultipartUploadComplete {
+    upload_id: String,
+    parts: Vec<MultipartUploadPart>,
+}
+
+// Relative paths are sent with `/` separators
+fn to_slash(path: String) -> String {
+    if cfg!(target_os = "windows") {
+        path.replace('\\', "/")
+    } else {
+        path
+    }
+}
+
+// Sent with the metadata post, the same for the retries of a post only, see `idempotency_key`
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+const DIR_MIME_TYPE: &str = "dir";
+const LINK_MIME_TYPE: &str = "link";
+
 impl UploadFileInfo {
-    fn get_value_by_map(&self, filed: UploadFileInfoFiled) -> Value {
+    // Empty directories and links are uploaded as metadata only
+    pub(crate) fn has_data(&self) -> bool {
+        self.mime_type != DIR_MIME_TYPE && self.mime_type != LINK_MIME_TYPE
+    }
+
+    // None if the filed is not sent
+    fn get_value_by_map(&self, filed: UploadFileInfoFiled) -> Option<Value> {
         match filed {
-            UploadFileInfoFiled::Name => json!(self.name),
-            UploadFileInfoFiled::RelativePath => {
-                #[cfg(target_os = "windows")]
-                {
-                    let relative_path = match self.relative_path.to_str() {
-                        Some(s) => s.replace("\\", "/"),
-                        None => {
-                            log::error!("path contains invalid UTF-8 characters");
-                            panic!("path contains invalid UTF-8 characters");
-                        }
-                    };
-                    json!(relative_path)
-                }
-                #[cfg(not(target_os = "windows"))]
-                {
-                    json!(self.relative_path)
+            UploadFileInfoFiled::Name => Some(json!(self.name)),
+            UploadFileInfoFiled::RelativePath => Some(json!(to_slash(
+                self.relative_path.to_string_lossy().into_owned()
+            ))),
+            UploadFileInfoFiled::Size => Some(json!(self.size)),
+            UploadFileInfoFiled::MimeType => Some(json!(self.mime_type)),
+            UploadFileInfoFiled::MimeTypeByContent => {
+                self.mime_type_by_content.as_ref().map(|mime| json!(mime))
+            }
+            UploadFileInfoFiled::MimeTypeByExtension => {
+                self.mime_type_by_extension.as_ref().map(|mime| json!(mime))
+            }
+            UploadFileInfoFiled::Sha256 => self.sha256.as_ref().map(|sha256| json!(sha256)),
+            UploadFileInfoFiled::Md5 => self.md5.as_ref().map(|md5| json!(md5)),
+            UploadFileInfoFiled::NameRaw => {
+                if self.name.is_empty() {
+                    return None;
                 }
+                path_codec::raw(self.path.file_name()?).map(|raw| json!(raw))
+            }
+            UploadFileInfoFiled::RelativePathRaw => {
+                path_codec::raw(self.relative_path.as_os_str()).map(|raw| json!(to_slash(raw)))
+            }
+            UploadFileInfoFiled::LinkTarget => self
+                .link_target
+                .as_ref()
+                .map(|link_target| json!(link_target.to_string_lossy())),
+            UploadFileInfoFiled::LinkTargetRaw => {
+                path_codec::raw(self.link_target.as_ref()?.as_os_str()).map(|raw| json!(raw))
             }
-            UploadFileInfoFiled::Size => json!(self.size),
-            UploadFileInfoFiled::MimeType => json!(self.mime_type),
         }
     }
     fn to_body(self, config: &FileUploadProcessParams) -> TardisResult<Value> {
         let mut value = json!({});
 
         for filed in UploadFileInfoFiled::get_all() {
-            if let Some(map_filed) = &config.upload_metadata_rename_filed {
-                if let Some(a) = map_filed.get(&filed) {
-                    value
-                        .as_object_mut()
-                        .expect("can't be here")
-                        .insert(a.to_string(), self.get_value_by_map(filed));
-                    continue;
-                }
-            }
-
-            value.as_object_mut().expect("can't be here").insert(
-                filed.to_str_filed().to_string(),
-                self.get_value_by_map(filed),
-            );
+            let Some(filed_value) = self.get_value_by_map(filed.clone()) else {
+                continue;
+            };
+            value
+                .as_object_mut()
+                .expect("can't be here")
+                .insert(filed.name(config), filed_value);
         }
         if let Some(fixed_fileds) = &config.upload_fixed_metadata {
             for fixed_filed in fixed_fileds {
@@ -136,126 +525,701 @@ impl UploadFileInfo {
 pub struct UploadStatsResp {
     pub total_file_numbers: usize,
     pub total_file_size: u64,
+    // the batch is not started if the files break the validation rules of the params
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<UploadViolation>,
+}
+
+// Cancellation tokens of the files of a batch by file id, children of the batch token
+type FileCancelMap = Arc<std::sync::Mutex<HashMap<String, CancellationToken>>>;
+
+struct BackgroundTask {
+    handle: TokioJoinHandle<()>,
+    cancel: CancellationToken,
+    scan_cancel: CancellationToken,
+    paused: watch::Sender<bool>,
+    files_cancel: FileCancelMap,
+}
+
+// Handed to the background task, controlled by the commands through `BACKGROUND_TASK`
+#[derive(Clone)]
+struct UploadControl {
+    cancel: CancellationToken,
+    // stops the scan only, the files found so far are still uploaded
+    scan_cancel: CancellationToken,
+    paused: watch::Receiver<bool>,
+    files_cancel: FileCancelMap,
+}
+
+impl UploadControl {
+    // Register a file of the batch so that it can be skipped
+    fn file_cancel(&self, id: &str) -> CancellationToken {
+        self.files_cancel
+            .lock()
+            .unwrap()
+            .entry(id.to_string())
+            .or_insert_with(|| self.cancel.child_token())
+            .clone()
+    }
+
+    // A finished file can't be skipped anymore
+    fn finish_file(&self, id: &str) {
+        self.files_cancel.lock().unwrap().remove(id);
+    }
+}
+
+// Shared by the uploads of a batch
+#[derive(Clone)]
+struct UploadContext {
+    config: FileUploadProcessParams,
+    retry: RetryConfig,
+    mime_source: MimeSource,
+    allowed_hosts: Vec<String>,
+    // redirects are checked against `allowed_hosts`
+    client: reqwest::Client,
+    tx: mpsc::Sender<UploadEvent>,
+    control: UploadControl,
+}
+
+static BACKGROUND_TASK: Lazy<Mutex<Option<BackgroundTask>>> = Lazy::new(|| Mutex::new(None));
+
+static LAST_BATCH: Lazy<Mutex<Option<FinishedBatch>>> = Lazy::new(|| Mutex::new(None));
+
+// Counters of a batch, a retry of its failed files continues from them
+#[derive(Debug, Clone, Copy, Default)]
+struct UploadCounter {
+    total_file_numbers: usize,
+    total_file_size: u64,
+    uploaded_file_numbers: usize,
+    uploaded_file_size: u64,
 }
 
-pub static BACKGROUND_TASK: Lazy<Mutex<Option<TokioJoinHandle<()>>>> =
-    Lazy::new(|| Mutex::new(None));
+#[derive(Debug, Clone)]
+struct FinishedBatch {
+    params: FileUploadProcessParams,
+    counter: UploadCounter,
+    summary: UploadSummaryResp,
+}
+
+// What a batch continues from, a retry of failed files continues the last batch
+#[derive(Debug, Clone, Default)]
+struct PreviousBatch {
+    counter: UploadCounter,
+    // finished files of the last batch, its failed files are being retried
+    summary: Option<UploadSummaryResp>,
+}
+
+// Files found by the scan wait here until an upload slot is free, so a huge folder holds back the scan
+// instead of keeping all of its files in memory
+const FILE_QUEUE_SIZE: usize = 1000;
+
+/// Where the files of a batch come from.
+pub enum UploadSource {
+    /// Scan the picked files and folders.
+    Scan {
+        files_uris: Vec<String>,
+        // finished by a previous run of the batch
+        skip_ids: HashSet<String>,
+    },
+    /// Files already known, e.g. recorded in the journal.
+    Files(Vec<UploadFileInfo>),
+}
+
+// Bytes read from the head of each file to sniff its mime type
+const SNIFF_SIZE: usize = 8192;
+
+// Minimal interval of `upload-scan` events, the first and last ones are always sent
+const SCAN_PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+// Sending end of the upload queue of a batch
+struct FileQueue {
+    tx: mpsc::Sender<UploadFileInfo>,
+    control: UploadControl,
+}
+
+// A scan of the picked files and folders feeding the upload queue
+struct FileScan {
+    queue: FileQueue,
+    // finished by a previous run of the batch
+    skip_ids: HashSet<String>,
+    filter: ScanFilter,
+    // sniff the content while scanning, only to validate mime types
+    sniff: Option<MimeSource>,
+    // real paths of the directories being scanned, to detect link cycles
+    ancestors: Vec<PathBuf>,
+    window: Window,
+    progress: UploadScanResp,
+    last_emit: Option<Instant>,
+}
+
+impl FileScan {
+    // The batch is cancelled or the user stopped the scan
+    fn is_stopped(&self) -> bool {
+        self.queue.tx.is_closed() || self.queue.control.scan_cancel.is_cancelled()
+    }
+
+    async fn push(&mut self, info: UploadFileInfo) {
+        // directories are counted when entered
+        if info.mime_type != DIR_MIME_TYPE {
+            self.progress.found_file_numbers += 1;
+            self.progress.found_file_size += info.size;
+        }
+        if !self.skip_ids.contains(&info.id) {
+            self.queue.push(info).await;
+        }
+    }
+
+    // Returns true if the entry is left out by the filters, which is then reported
+    fn exclude(&mut self, path: &Path, base_path: &Path, is_dir: bool) -> bool {
+        // patterns match the path inside the picked folder, without the name of the folder
+        let folder_path = path
+            .strip_prefix(base_path)
+            .unwrap_or(path)
+            .components()
+            .skip(1)
+            .collect::<PathBuf>();
+        let Some((reason, rule)) = self.filter.check(path, &folder_path, is_dir) else {
+            return false;
+        };
+        self.report(path, base_path, is_dir, reason, rule);
+        true
+    }
+
+    fn report(
+        &mut self,
+        path: &Path,
+        base_path: &Path,
+        is_dir: bool,
+        reason: UploadExcludeReason,
+        rule: String,
+    ) {
+        let relative_path = path.strip_prefix(base_path).unwrap_or(path);
+        if is_dir {
+            self.progress.excluded_dir_numbers += 1;
+        } else {
+            self.progress.excluded_file_numbers += 1;
+        }
+        self.progress.excluded.push(UploadExcludedEntry {
+            relative_path: relative_path.to_path_buf(),
+            is_dir,
+            reason,
+            rule,
+        });
+    }
+
+    async fn enter_dir(&mut self, path: &Path, base_path: &Path, real_path: PathBuf) {
+        self.ancestors.push(real_path);
+        self.filter.enter_dir(path).await;
+        self.progress.found_dir_numbers += 1;
+        self.progress.current_dir = path.strip_prefix(base_path).unwrap_or(path).to_path_buf();
+        self.emit(false);
+    }
+
+    fn leave_dir(&mut self) {
+        self.filter.leave_dir();
+        self.ancestors.pop();
+    }
+
+    fn finish(&mut self) {
+        self.progress.finished = true;
+        self.progress.cancelled = self.queue.control.scan_cancel.is_cancelled();
+        self.emit(true);
+    }
 
+    fn emit(&mut self, force: bool) {
+        if !force
+            && self
+                .last_emit
+                .is_some_and(|last_emit| last_emit.elapsed() < SCAN_PROGRESS_INTERVAL)
+        {
+            return;
+        }
+        self.last_emit = Some(Instant::now());
+        if let Err(e) = self.window.emit("upload-scan", self.progress.clone()) {
+            error!("emit scan progress fail:{e}");
+        }
+        self.progress.excluded.clear();
+    }
+}
+
+impl FileQueue {
+    // Returns false once the queue is closed, i.e. the batch is cancelled
+    async fn push(&self, info: UploadFileInfo) -> bool {
+        // queued files can be skipped before their upload starts
+        self.control.file_cancel(&info.id);
+        self.tx.send(info).await.is_ok()
+    }
+}
+
+/// Start uploading the picked files and folders, uploads begin while the folders are still scanned
+/// and the growing totals are reported by `upload-progress`.
 pub async fn upload_files(
     files_uris: Vec<String>,
     window: Window,
 ) -> TardisResult<UploadStatsResp> {
-    let mut total_file_numbers = 0;
-    let mut total_file_size: u64 = 0;
+    let param = crate::get_params();
+    let Some(upload) = param.upload else {
+        return Ok(UploadStatsResp {
+            total_file_numbers: 0,
+            total_file_size: 0,
+            violations: vec![],
+        });
+    };
+    ensure_idle()?;
+    let mock = is_mock(&param.title);
+    if !mock {
+        check_hosts(&upload)?;
+    }
+    let source = || UploadSource::Scan {
+        files_uris: files_uris.clone(),
+        skip_ids: HashSet::new(),
+    };
+    if let Some(rules) = &upload.upload_validation {
+        // the files are scanned again to be uploaded, rather than kept for a batch of any size
+        if let Err(violations) = validate_files(source(), rules.clone(), &window, &upload).await? {
+            return Ok(UploadStatsResp {
+                total_file_numbers: 0,
+                total_file_size: 0,
+                violations,
+            });
+        }
+    }
+    let journal = if mock {
+        None
+    } else {
+        Some(UploadJournal::create(&window, &upload, &files_uris).await?)
+    };
+    start_upload(
+        source(),
+        PreviousBatch::default(),
+        journal,
+        window,
+        upload,
+        mock,
+    )
+}
 
+/// Continue the unfinished batch recorded in the journal, only files not yet uploaded successfully are sent.
+pub async fn resume_upload(window: Window) -> TardisResult<UploadStatsResp> {
     let param = crate::get_params();
-    if let Some(upload) = param.upload {
-        let mut files = Vec::new();
-        for file_uri in files_uris {
-            let origin_path = PathBuf::from(&file_uri);
-            let base_path = origin_path.parent().unwrap_or(Path::new(""));
-            let paths = async_get_files(&file_uri).await?;
-            for path in paths {
-                let relative_path = path
-                    .strip_prefix(&base_path)
-                    .map_err(|e| TardisError::io_error(&format!("io error:{e}"), "error"))?;
-                if path.is_file() {
-                    let mime_type = mime_infer::from_path(path.clone()).first_or_text_plain();
-                    let file = File::open(path.clone())
-                        .await
-                        .map_err(|e| TardisError::io_error(&format!("io error:{e}"), "error"))?;
-                    let size;
-                    #[cfg(any(target_os = "macos", target_os = "linux"))]
-                    {
-                        size = file.metadata().await?.size();
-                    }
-                    #[cfg(target_os = "windows")]
-                    {
-                        size = file.metadata().await?.file_size();
-                    }
-                    let info = UploadFileInfo {
-                        name: path
-                            .file_name()
-                            .and_then(|s| s.to_str())
-                            .unwrap_or_default()
-                            .to_string(),
-                        relative_path: relative_path.to_path_buf(),
-                        size,
-                        mime_type: mime_type.to_string(),
-                        id: random::<u64>().to_string(),
-                    };
-
-                    files.push((Some(file), info));
-                } else {
-                    files.push((
-                        None,
-                        UploadFileInfo {
-                            name: "".to_string(),
-                            relative_path: relative_path.to_path_buf(),
-                            size: 0,
-                            mime_type: "dir".to_string(),
-                            id: random::<u64>().to_string(),
-                        },
-                    ));
-                }
+    let Some(upload) = param.upload else {
+        return Err(TardisError::bad_request(
+            "no upload params",
+            "400-upload-params-missing",
+        ));
+    };
+    ensure_idle()?;
+    let Some((journal, source)) = UploadJournal::open(&window, &upload).await? else {
+        return Err(TardisError::not_found(
+            "no upload to resume",
+            "404-upload-journal-not-found",
+        ));
+    };
+    check_hosts(&upload)?;
+    start_upload(
+        source,
+        PreviousBatch::default(),
+        Some(journal),
+        window,
+        upload,
+        false,
+    )
+}
+
+/// Upload the failed files of the last finished batch again, counters continue from that batch.
+pub async fn retry_failed_files(window: Window) -> TardisResult<UploadStatsResp> {
+    ensure_idle()?;
+    let Some(batch) = LAST_BATCH.lock().await.clone() else {
+        return Err(TardisError::not_found(
+            "no finished upload",
+            "404-upload-batch-not-found",
+        ));
+    };
+    let mut summary = batch.summary;
+    let fail_files = std::mem::take(&mut summary.fail_files)
+        .into_iter()
+        .map(|fail_file| fail_file.file)
+        .collect::<Vec<_>>();
+    if fail_files.is_empty() {
+        return Err(TardisError::bad_request(
+            "no failed files to retry",
+            "400-upload-no-failed-files",
+        ));
+    }
+    // the failed files are counted again as they are queued
+    let total_file_numbers = batch.counter.total_file_numbers - fail_files.len();
+    let total_file_size =
+        batch.counter.total_file_size - fail_files.iter().map(|info| info.size).sum::<u64>();
+    let counter = UploadCounter {
+        total_file_numbers,
+        total_file_size,
+        uploaded_file_numbers: total_file_numbers,
+        uploaded_file_size: total_file_size,
+    };
+    let journal = UploadJournal::open(&window, &batch.params)
+        .await?
+        .map(|(journal, _)| journal);
+    check_hosts(&batch.params)?;
+    start_upload(
+        UploadSource::Files(fail_files),
+        PreviousBatch {
+            counter,
+            summary: Some(summary),
+        },
+        journal,
+        window,
+        batch.params,
+        false,
+    )
+}
+
+fn start_upload(
+    source: UploadSource,
+    previous: PreviousBatch,
+    journal: Option<UploadJournal>,
+    window: Window,
+    upload: FileUploadProcessParams,
+    mock: bool,
+) -> TardisResult<UploadStatsResp> {
+    let UploadCounter {
+        total_file_numbers,
+        total_file_size,
+        ..
+    } = previous.counter;
+    let filter = scan_filter(&upload)?;
+    let processor_config = TardisFuns::cs_config::<ProcessorConfig>(DOMAIN_CODE);
+    let client = host_policy::client(processor_config.allowed_hosts.clone())?;
+    spawn_background_task(|control| {
+        let (files_tx, files_rx) = mpsc::channel(FILE_QUEUE_SIZE);
+        spawn(queue_files(
+            source,
+            FileQueue {
+                tx: files_tx,
+                control: control.clone(),
+            },
+            filter,
+            None,
+            window.clone(),
+        ));
+        async move {
+            if mock {
+                mock_backend_task(files_rx, window, upload, control).await
+            } else {
+                backend_task(files_rx, previous, window, upload, journal, client, control).await
             }
         }
-        total_file_numbers = files.len();
-        total_file_size = stream::iter(&files)
-            .then(|(file, _)| get_metadata_size(file))
-            .collect::<Vec<u64>>()
-            .await
-            .into_iter()
-            .sum();
-        let back_task;
-        if param.title.eq("请按使用文档调用（以下为示例）") {
-            //mock
-            back_task = spawn(async move {
-                mock_backend_task(files, total_file_numbers, total_file_size, window, upload).await;
-            });
-        } else {
-            back_task = spawn(async move {
-                backend_task(files, total_file_numbers, total_file_size, window, upload).await
+    })?;
+
+    Ok(UploadStatsResp {
+        total_file_numbers,
+        total_file_size,
+        violations: vec![],
+    })
+}
+
+// The allow-list may have changed since the journal or the batch was created
+fn check_hosts(upload: &FileUploadProcessParams) -> TardisResult<()> {
+    let processor_config = TardisFuns::cs_config::<ProcessorConfig>(DOMAIN_CODE);
+    host_policy::check_params(upload, &processor_config.allowed_hosts)
+}
+
+fn scan_filter(upload: &FileUploadProcessParams) -> TardisResult<ScanFilter> {
+    let processor_config = TardisFuns::cs_config::<ProcessorConfig>(DOMAIN_CODE);
+    ScanFilter::new(upload, &processor_config.filter)
+}
+
+fn mime_source(upload: &FileUploadProcessParams) -> MimeSource {
+    let processor_config = TardisFuns::cs_config::<ProcessorConfig>(DOMAIN_CODE);
+    upload
+        .upload_mime_source
+        .unwrap_or(processor_config.mime_source)
+}
+
+// Scan the whole batch before any upload, returns the broken rules if any.
+// Only counters are kept of the files. Stopping the scan stops the batch, the rules on
+// the whole batch can't be checked against part of it.
+async fn validate_files(
+    source: UploadSource,
+    rules: UploadValidation,
+    window: &Window,
+    upload: &FileUploadProcessParams,
+) -> TardisResult<Result<(), Vec<UploadViolation>>> {
+    let filter = scan_filter(upload)?;
+    let sniff = rules.checks_mime().then(|| mime_source(upload));
+    let (result_tx, result_rx) = oneshot::channel();
+    spawn_background_task(|control| {
+        let (files_tx, mut files_rx) = mpsc::channel(FILE_QUEUE_SIZE);
+        spawn(queue_files(
+            source,
+            FileQueue {
+                tx: files_tx,
+                control: control.clone(),
+            },
+            filter,
+            sniff,
+            window.clone(),
+        ));
+        async move {
+            let mut validator = Validator::new(rules);
+            loop {
+                tardis::tokio::select! {
+                    info = files_rx.recv() => match info {
+                        Some(info) => validator.check(&info),
+                        None => break,
+                    },
+                    _ = control.cancel.cancelled() => return,
+                }
+            }
+            if control.scan_cancel.is_cancelled() {
+                return;
+            }
+            let violations = validator.finish();
+            let _ = result_tx.send(if violations.is_empty() {
+                Ok(())
+            } else {
+                Err(violations)
             });
         }
-        let mut guard = BACKGROUND_TASK
-            .try_lock()
-            .ok_or(TardisError::io_error(&format!("try lock error"), "error"))?;
-        *guard = Some(back_task);
+    })?;
+    result_rx
+        .await
+        .map_err(|_| TardisError::bad_request("upload cancelled", "400-upload-cancelled"))
+}
+
+// Feed the files of a batch to the upload queue until all are queued or the queue is closed
+async fn queue_files(
+    source: UploadSource,
+    queue: FileQueue,
+    filter: ScanFilter,
+    sniff: Option<MimeSource>,
+    window: Window,
+) {
+    match source {
+        UploadSource::Files(infos) => {
+            for info in infos {
+                if !queue.push(info).await {
+                    return;
+                }
+            }
+        }
+        UploadSource::Scan {
+            files_uris,
+            skip_ids,
+        } => {
+            let mut scan = FileScan {
+                queue,
+                skip_ids,
+                filter,
+                sniff,
+                ancestors: vec![],
+                window,
+                progress: UploadScanResp::default(),
+                last_emit: None,
+            };
+            for file_uri in files_uris {
+                let path = PathBuf::from(&file_uri);
+                let base_path = path.parent().unwrap_or(Path::new(""));
+                if let Err(e) = async_get_files(&path, base_path, &mut scan).await {
+                    error!("scan {file_uri} fail:{e}");
+                    break;
+                }
+                if scan.is_stopped() {
+                    break;
+                }
+            }
+            scan.finish();
+        }
     }
+}
 
-    Ok(UploadStatsResp {
-        total_file_numbers: total_file_numbers,
-        total_file_size: total_file_size,
-    })
+// Held only briefly by the commands, a busy lock is reported rather than waited for
+fn lock_background_task() -> TardisResult<MutexGuard<'static, Option<BackgroundTask>>> {
+    BACKGROUND_TASK
+        .try_lock()
+        .ok_or_else(|| TardisError::io_error("try lock error", "error"))
+}
+
+// Refuse to start a task that would cancel the running one
+fn ensure_idle() -> TardisResult<()> {
+    let guard = lock_background_task()?;
+    if guard
+        .as_ref()
+        .is_some_and(|task| !task.handle.is_finished())
+    {
+        return Err(TardisError::conflict(
+            "an upload is still running",
+            "409-upload-running",
+        ));
+    }
+    Ok(())
+}
+
+fn spawn_background_task<F, Fut>(task: F) -> TardisResult<()>
+where
+    F: FnOnce(UploadControl) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let cancel = CancellationToken::new();
+    let scan_cancel = cancel.child_token();
+    let (paused, paused_rx) = watch::channel(false);
+    let files_cancel = FileCancelMap::default();
+    let handle = spawn(task(UploadControl {
+        cancel: cancel.clone(),
+        scan_cancel: scan_cancel.clone(),
+        paused: paused_rx,
+        files_cancel: files_cancel.clone(),
+    }));
+    let mut guard = lock_background_task()?;
+    if let Some(previous) = guard.take() {
+        if !previous.handle.is_finished() {
+            previous.cancel.cancel();
+        }
+    }
+    *guard = Some(BackgroundTask {
+        handle,
+        cancel,
+        scan_cancel,
+        paused,
+        files_cancel,
+    });
+    Ok(())
+}
+
+/// Stop the running batch, in-flight uploads are dropped and reported as cancelled.
+pub async fn cancel() -> TardisResult<()> {
+    let guard = lock_background_task()?;
+    if let Some(task) = &(*guard) {
+        task.cancel.cancel();
+    }
+    Ok(())
+}
+
+/// Stop scanning the picked folders, the files found so far are still uploaded.
+pub async fn cancel_scan() -> TardisResult<()> {
+    let guard = lock_background_task()?;
+    if let Some(task) = &(*guard) {
+        task.scan_cancel.cancel();
+    }
+    Ok(())
+}
+
+/// Skip one file of the running batch, it is dropped from the queue or its upload is stopped.
+pub async fn skip_file(id: String) -> TardisResult<()> {
+    let guard = lock_background_task()?;
+    let file_cancel = guard
+        .as_ref()
+        .and_then(|task| task.files_cancel.lock().unwrap().get(&id).cloned());
+    let Some(file_cancel) = file_cancel else {
+        return Err(TardisError::not_found(
+            &format!("file {id} is not in progress"),
+            "404-upload-file-not-in-progress",
+        ));
+    };
+    file_cancel.cancel();
+    Ok(())
+}
+
+/// Stop starting new files and suspend the data of in-flight uploads, counters are kept.
+pub async fn pause() -> TardisResult<()> {
+    set_paused(true)
+}
+
+pub async fn resume() -> TardisResult<()> {
+    set_paused(false)
+}
+
+fn set_paused(paused: bool) -> TardisResult<()> {
+    let guard = lock_background_task()?;
+    if let Some(task) = &(*guard) {
+        task.paused.send_replace(paused);
+    }
+    Ok(())
+}
+
+fn is_mock(title: &str) -> bool {
+    title.eq("请按使用文档调用（以下为示例）")
+}
+
+// Stable across restarts so that the journal can match files of a resumed batch
+fn file_id(path: &Path) -> String {
+    // lossy paths may collide
+    let digest = Sha256::digest(path_codec::escape(path.as_os_str()).as_bytes());
+    hex::encode(&digest[..8])
 }
 
 async fn mock_backend_task(
-    files: Vec<(Option<File>, UploadFileInfo)>,
-    total_file_numbers: usize,
-    total_file_size: u64,
+    mut files_rx: mpsc::Receiver<UploadFileInfo>,
     window: Window,
     _config: FileUploadProcessParams,
+    control: UploadControl,
 ) {
+    let mut total_file_numbers = 0;
+    let mut total_file_size = 0;
     let mut uploaded_file_numbers = 0;
     let mut uploaded_file_size = 0;
 
     let mut last_file: Option<UploadFileInfo> = None;
-    for (_file, info) in files {
+    while let Some(info) = files_rx.recv().await {
+        total_file_numbers += 1;
+        total_file_size += info.size;
+        let file_cancel = control.file_cancel(&info.id);
         tardis::tokio::time::sleep(Duration::from_secs(1)).await;
+        wait_resumed(&mut control.paused.clone()).await;
+        if control.cancel.is_cancelled() {
+            break;
+        }
         uploaded_file_numbers += 1;
         uploaded_file_size += info.size;
+        control.finish_file(&info.id);
+        if file_cancel.is_cancelled() {
+            window
+                .emit(
+                    "upload-progress",
+                    UploadProgressResp {
+                        total_file_numbers,
+                        total_file_size,
+                        scanning: true,
+                        uploaded_file_numbers,
+                        uploaded_file_size,
+                        paused: false,
+                        current_files: vec![],
+                        current_parts: vec![],
+                        current_retries: vec![],
+                        fail_files: vec![],
+                        success_files: vec![],
+                        cancelled_files: vec![],
+                        skipped_files: vec![info],
+                        deduplicated_files: vec![],
+                        deduplicated_file_size: 0,
+                    },
+                )
+                .unwrap();
+            continue;
+        }
         if let Some(last_file) = &last_file {
             if random() {
                 window
                     .emit(
                         "upload-progress",
                         UploadProgressResp {
+                            total_file_numbers,
+                            total_file_size,
+                            scanning: true,
                             uploaded_file_numbers,
                             uploaded_file_size,
+                            paused: false,
                             current_files: vec![info.clone()],
-                            fail_files: vec![last_file.clone()],
+                            current_parts: vec![],
+                            current_retries: vec![],
+                            fail_files: vec![UploadFailFileInfo {
+                                file: last_file.clone(),
+                                failure: UploadFailure::new(UploadFailStage::Upload, "mock fail"),
+                            }],
                             success_files: vec![],
+                            cancelled_files: vec![],
+                            skipped_files: vec![],
+                            deduplicated_files: vec![],
+                            deduplicated_file_size: 0,
                         },
                     )
                     .unwrap();
@@ -264,11 +1228,21 @@ async fn mock_backend_task(
                     .emit(
                         "upload-progress",
                         UploadProgressResp {
+                            total_file_numbers,
+                            total_file_size,
+                            scanning: true,
                             uploaded_file_numbers,
                             uploaded_file_size,
+                            paused: false,
                             current_files: vec![info.clone()],
+                            current_parts: vec![],
+                            current_retries: vec![],
                             fail_files: vec![],
                             success_files: vec![last_file.clone()],
+                            cancelled_files: vec![],
+                            skipped_files: vec![],
+                            deduplicated_files: vec![],
+                            deduplicated_file_size: 0,
                         },
                     )
                     .unwrap();
@@ -278,11 +1252,21 @@ async fn mock_backend_task(
                 .emit(
                     "upload-progress",
                     UploadProgressResp {
+                        total_file_numbers,
+                        total_file_size,
+                        scanning: true,
                         uploaded_file_numbers,
                         uploaded_file_size,
+                        paused: false,
                         current_files: vec![info.clone()],
+                        current_parts: vec![],
+                        current_retries: vec![],
                         fail_files: vec![],
                         success_files: vec![],
+                        cancelled_files: vec![],
+                        skipped_files: vec![],
+                        deduplicated_files: vec![],
+                        deduplicated_file_size: 0,
                     },
                 )
                 .unwrap();
@@ -294,176 +1278,1317 @@ async fn mock_backend_task(
         .emit(
             "upload-progress",
             UploadProgressResp {
+                total_file_numbers,
+                total_file_size,
+                scanning: false,
                 uploaded_file_numbers: total_file_numbers,
                 uploaded_file_size: total_file_size,
+                paused: false,
                 current_files: vec![],
+                current_parts: vec![],
+                current_retries: vec![],
                 fail_files: vec![],
                 success_files: if last_file.is_some() {
                     vec![last_file.unwrap()]
                 } else {
                     vec![]
                 },
+                cancelled_files: vec![],
+                skipped_files: vec![],
+                deduplicated_files: vec![],
+                deduplicated_file_size: 0,
             },
         )
         .unwrap();
 }
 
 async fn backend_task(
-    files: Vec<(Option<File>, UploadFileInfo)>,
-    total_file_numbers: usize,
-    total_file_size: u64,
+    mut files_rx: mpsc::Receiver<UploadFileInfo>,
+    previous: PreviousBatch,
     window: Window,
     config: FileUploadProcessParams,
+    mut journal: Option<UploadJournal>,
+    client: reqwest::Client,
+    control: UploadControl,
 ) {
-    let mut uploaded_file_numbers = 0;
-    let mut uploaded_file_size = 0;
+    let UploadCounter {
+        mut total_file_numbers,
+        mut total_file_size,
+        mut uploaded_file_numbers,
+        mut uploaded_file_size,
+    } = previous.counter;
+    let previous = previous.summary;
+    let mut summary_success_files = previous
+        .as_ref()
+        .map(|summary| summary.success_files.clone())
+        .unwrap_or_default();
+    let mut summary_fail_files = Vec::new();
+    let mut summary_cancelled_files = previous
+        .as_ref()
+        .map(|summary| summary.cancelled_files.clone())
+        .unwrap_or_default();
+    let mut summary_skipped_files = previous
+        .as_ref()
+        .map(|summary| summary.skipped_files.clone())
+        .unwrap_or_default();
+    let mut summary_deduplicated_files = previous
+        .as_ref()
+        .map(|summary| summary.deduplicated_files.clone())
+        .unwrap_or_default();
+    let mut deduplicated_file_size = previous
+        .as_ref()
+        .map(|summary| summary.deduplicated_file_size)
+        .unwrap_or_default();
 
-    // first boolean means end(true)/start
-    // seconde boolean is success(true)/fail
-    let (tx, mut rx) = mpsc::channel(50);
-    let max_concurrent_tasks = TardisFuns::cs_config::<ProcessorConfig>(DOMAIN_CODE).concurrent;
-    let semaphore = Arc::new(Semaphore::new(max_concurrent_tasks));
-
-    for (file, info) in files {
-        tardis::tokio::task::yield_now().await;
-        let n_tx = tx.clone();
-        let config = config.clone();
-        let semaphore = semaphore.clone();
-
-        spawn(async move {
-            let permit = semaphore.acquire_owned().await.unwrap();
-            let _ = n_tx.send(((false, false), info.clone())).await;
-            let body = info.clone().to_body(&config).unwrap();
-            info!("file.body:{}", body);
-            if let Some(mut file) = file {
-                if let Ok(upload_metadata_result) = TardisFuns::web_client()
-                    .post_obj_to_str(
-                        config.upload_metadata_url,
-                        &body,
-                        config.upload_fixed_headers.unwrap_or_default(),
-                    )
-                    .await
-                {
-                    info!("upload_metadata_result:{:?}", upload_metadata_result);
-                    if upload_metadata_result.code == 200 {
-                        if let Some(upload_url) = upload_metadata_result.body {
-                            info!("upload_url:{:?}", upload_url);
-                            if reqwest::Url::parse(&upload_url).is_err() {
-                                let _ = n_tx.send(((true, false), info.clone())).await;
-                                return;
-                            }
-
-                            let mut content = vec![];
-                            let _ = file.read_to_end(&mut content).await;
-                            let client = reqwest::Client::new();
-                            if let Ok(_) = client.put(upload_url).body(content).send().await {
-                                let _ = n_tx.send(((true, true), info.clone())).await;
-                                return;
-                            }
-                        }
-                    }
-                };
-                let _ = n_tx.send(((true, false), info.clone())).await;
-                drop(permit);
-            } else {
-                //empty dir
-                let _ = TardisFuns::web_client()
-                    .post_obj_to_str(
-                        config.upload_metadata_url,
-                        &body,
-                        config.upload_fixed_headers.unwrap_or_default(),
-                    )
-                    .await;
-                let _ = n_tx.send(((true, true), info.clone())).await;
-            }
-        });
-    }
+    let (tx, mut rx) = mpsc::channel::<UploadEvent>(50);
+    let processor_config = TardisFuns::cs_config::<ProcessorConfig>(DOMAIN_CODE);
+    let semaphore = Arc::new(Semaphore::new(processor_config.concurrent));
+    let ctx = UploadContext {
+        config: config.clone(),
+        retry: processor_config.retry.clone(),
+        mime_source: mime_source(&config),
+        allowed_hosts: processor_config.allowed_hosts.clone(),
+        client,
+        tx,
+        control: control.clone(),
+    };
 
+    let mut scanning = true;
     let mut current_files_map = HashMap::new();
-    while let Some(((is_done, is_success), i)) = rx.recv().await {
+    let mut paused_rx = control.paused.clone();
+    let mut paused = *paused_rx.borrow_and_update();
+    loop {
+        // `rx` never ends as `ctx` keeps a sender
+        let event = tardis::tokio::select! {
+            // no file is started once cancelled
+            biased;
+            _ = control.cancel.cancelled(), if scanning => UploadEvent::Next(None),
+            // only take the next file when it can start, so that the queue holds back the scan
+            next = next_file(&semaphore, &mut files_rx), if scanning => UploadEvent::Next(next),
+            Some(event) = rx.recv() => event,
+            Ok(()) = paused_rx.changed() => UploadEvent::Paused(*paused_rx.borrow_and_update()),
+        };
         let mut success_files = Vec::new();
         let mut fail_files = Vec::new();
-        if uploaded_file_numbers == total_file_numbers {
-            break;
-        }
-        if is_done {
-            current_files_map.remove(&i.id);
-            uploaded_file_numbers += 1;
-            uploaded_file_size += i.size;
-            if is_success {
-                success_files.push(i)
-            } else {
-                fail_files.push(i)
+        let mut current_parts = Vec::new();
+        let mut current_retries = Vec::new();
+        let mut cancelled_files = Vec::new();
+        let mut skipped_files = Vec::new();
+        let mut deduplicated_files = Vec::new();
+        match event {
+            UploadEvent::Next(Some((permit, info))) => {
+                total_file_numbers += 1;
+                total_file_size += info.size;
+                if let Some(journal) = &mut journal {
+                    journal.add_file(&info).await;
+                }
+                spawn_upload(info, permit, ctx.clone());
+            }
+            UploadEvent::Next(None) => {
+                scanning = false;
+                // stops the scan if cancelled
+                files_rx.close();
+                // the files queued but not taken are reported as cancelled, pending in the journal
+                while let Ok(info) = files_rx.try_recv() {
+                    total_file_numbers += 1;
+                    total_file_size += info.size;
+                    uploaded_file_numbers += 1;
+                    uploaded_file_size += info.size;
+                    if let Some(journal) = &mut journal {
+                        journal.add_file(&info).await;
+                    }
+                    control.finish_file(&info.id);
+                    summary_cancelled_files.push(info.clone());
+                    cancelled_files.push(info);
+                }
+                if !control.cancel.is_cancelled() {
+                    if let Some(journal) = &journal {
+                        journal.finish_scan().await;
+                    }
+                }
+            }
+            UploadEvent::Start(i) => {
+                current_files_map.insert(i.id.clone(), i);
+            }
+            UploadEvent::Part(part) => current_parts.push(part),
+            UploadEvent::Retry(retry) => current_retries.push(retry),
+            UploadEvent::Paused(is_paused) => {
+                info!("upload paused:{is_paused}");
+                paused = is_paused;
+            }
+            UploadEvent::Done(i, outcome) => {
+                current_files_map.remove(&i.id);
+                control.finish_file(&i.id);
+                uploaded_file_numbers += 1;
+                uploaded_file_size += i.size;
+                // cancelled files are kept pending in the journal so that they can be resumed
+                if let Some(journal) = &mut journal {
+                    match &outcome {
+                        UploadOutcome::Success | UploadOutcome::Deduplicated => {
+                            journal.record(&i.id, UploadFileState::Success).await
+                        }
+                        UploadOutcome::Fail(_) => {
+                            journal.record(&i.id, UploadFileState::Fail).await
+                        }
+                        UploadOutcome::Skipped => {
+                            journal.record(&i.id, UploadFileState::Skipped).await
+                        }
+                        UploadOutcome::Cancelled => {}
+                    }
+                }
+                match outcome {
+                    UploadOutcome::Success => {
+                        summary_success_files.push(i.clone());
+                        success_files.push(i)
+                    }
+                    UploadOutcome::Fail(failure) => {
+                        let fail_file = UploadFailFileInfo { file: i, failure };
+                        summary_fail_files.push(fail_file.clone());
+                        fail_files.push(fail_file)
+                    }
+                    UploadOutcome::Cancelled => {
+                        summary_cancelled_files.push(i.clone());
+                        cancelled_files.push(i)
+                    }
+                    UploadOutcome::Skipped => {
+                        summary_skipped_files.push(i.clone());
+                        skipped_files.push(i)
+                    }
+                    UploadOutcome::Deduplicated => {
+                        deduplicated_file_size += i.size;
+                        summary_deduplicated_files.push(i.clone());
+                        deduplicated_files.push(i)
+                    }
+                }
             }
-        } else {
-            current_files_map.insert(i.id.clone(), i);
         }
 
         window
             .emit(
                 "upload-progress",
                 UploadProgressResp {
+                    total_file_numbers,
+                    total_file_size,
+                    scanning,
                     uploaded_file_numbers,
                     uploaded_file_size,
+                    paused,
                     current_files: current_files_map
                         .iter()
                         .map(|(_, info)| info.clone())
                         .collect(),
+                    current_parts,
+                    current_retries,
                     fail_files,
                     success_files,
+                    cancelled_files,
+                    skipped_files,
+                    deduplicated_files,
+                    deduplicated_file_size,
                 },
             )
             .unwrap();
+        if !scanning && uploaded_file_numbers == total_file_numbers {
+            break;
+        }
+    }
+
+    info!(
+        "upload finished, success:{}, fail:{}, cancelled:{}, skipped:{}, deduplicated:{}({deduplicated_file_size} bytes)",
+        summary_success_files.len(),
+        summary_fail_files.len(),
+        summary_cancelled_files.len(),
+        summary_skipped_files.len(),
+        summary_deduplicated_files.len()
+    );
+    for fail_file in &summary_fail_files {
+        info!(
+            "upload fail {:?}:{}",
+            fail_file.file.relative_path, fail_file.failure
+        );
+    }
+    if !control.cancel.is_cancelled()
+        && summary_fail_files.is_empty()
+        && summary_cancelled_files.is_empty()
+    {
+        if let Some(journal) = journal {
+            journal.remove().await;
+        }
     }
+    let summary = UploadSummaryResp {
+        total_file_numbers,
+        cancelled: control.cancel.is_cancelled(),
+        success_files: summary_success_files,
+        fail_files: summary_fail_files,
+        cancelled_files: summary_cancelled_files,
+        skipped_files: summary_skipped_files,
+        deduplicated_files: summary_deduplicated_files,
+        deduplicated_file_size,
+    };
+    *LAST_BATCH.lock().await = Some(FinishedBatch {
+        params: config,
+        counter: UploadCounter {
+            total_file_numbers,
+            total_file_size,
+            uploaded_file_numbers,
+            uploaded_file_size,
+        },
+        summary: summary.clone(),
+    });
 
     window
         .emit(
             "upload-progress",
             UploadProgressResp {
-                uploaded_file_numbers: total_file_numbers,
-                uploaded_file_size: total_file_size,
+                total_file_numbers,
+                total_file_size,
+                scanning: false,
+                uploaded_file_numbers,
+                uploaded_file_size,
+                paused: false,
                 current_files: vec![],
+                current_parts: vec![],
+                current_retries: vec![],
                 fail_files: vec![],
                 success_files: vec![],
+                cancelled_files: vec![],
+                skipped_files: vec![],
+                deduplicated_files: vec![],
+                deduplicated_file_size,
             },
         )
         .unwrap();
+    window.emit("upload-summary", summary).unwrap();
 }
 
-async fn get_metadata_size(file: &Option<File>) -> u64 {
-    if let Some(file) = file {
-        file.metadata().await.map(|md| md.len()).unwrap_or_default()
-    } else {
-        0
+// Waits for a free upload slot, then for the next queued file, none once the queue is finished
+async fn next_file(
+    semaphore: &Arc<Semaphore>,
+    files_rx: &mut mpsc::Receiver<UploadFileInfo>,
+) -> Option<(OwnedSemaphorePermit, UploadFileInfo)> {
+    let permit = semaphore.clone().acquire_owned().await.ok()?;
+    files_rx.recv().await.map(|info| (permit, info))
+}
+
+fn spawn_upload(mut info: UploadFileInfo, permit: OwnedSemaphorePermit, ctx: UploadContext) {
+    let file_cancel = ctx.control.file_cancel(&info.id);
+    spawn(async move {
+        let _permit = permit;
+        // upload registered at the server but not finished, aborted if cancelled
+        let pending = std::sync::Mutex::new(None);
+        // also cancelled with the batch
+        let cancelled = || {
+            if ctx.control.cancel.is_cancelled() {
+                UploadOutcome::Cancelled
+            } else {
+                UploadOutcome::Skipped
+            }
+        };
+        let outcome = tardis::tokio::select! {
+            // a file skipped while queued doesn't start
+            biased;
+            _ = file_cancel.cancelled() => cancelled(),
+            outcome = async {
+                // don't start new files while paused
+                wait_resumed(&mut ctx.control.paused.clone()).await;
+                // cancelled while paused, resumed in the same poll
+                if file_cancel.is_cancelled() {
+                    return cancelled();
+                }
+                let _ = ctx.tx.send(UploadEvent::Start(info.clone())).await;
+                match upload_file(&mut info, &ctx, &pending).await {
+                    Ok(outcome) => outcome,
+                    Err(failure) => {
+                        error!("upload {:?} fail:{failure}", info.relative_path);
+                        UploadOutcome::Fail(failure)
+                    }
+                }
+            } => outcome,
+        };
+        // a failed upload would otherwise stay open at the server, e.g. a multipart upload
+        if let UploadOutcome::Cancelled | UploadOutcome::Skipped | UploadOutcome::Fail(_) = outcome
+        {
+            let pending_upload = pending.lock().unwrap().take();
+            if let Some((abort_url, abort_body)) = pending_upload {
+                abort_upload(&info, abort_url, &abort_body, &ctx).await;
+            }
+        }
+        let _ = ctx.tx.send(UploadEvent::Done(info, outcome)).await;
+    });
+}
+
+// The hashes computed for the metadata are kept in `info`.
+// Returns `Success` or `Deduplicated`, the failure otherwise.
+async fn upload_file(
+    info: &mut UploadFileInfo,
+    ctx: &UploadContext,
+    pending: &std::sync::Mutex<Option<(String, Value)>>,
+) -> Result<UploadOutcome, UploadFailure> {
+    let config = &ctx.config;
+    check_readable(info).await?;
+    if info.has_data() {
+        sniff_info(info, ctx.mime_source).await;
+    }
+    // the files are scanned again once validated, they may have changed since
+    if let Some(violation) = config
+        .upload_validation
+        .as_ref()
+        .and_then(|rules| rules.file_violations(info).into_iter().next())
+    {
+        return Err(UploadFailure::new(
+            UploadFailStage::Validation,
+            violation.message,
+        ));
+    }
+    // storages report the md5 as ETag
+    let md5 = config.upload_verify.is_some()
+        || config
+            .upload_hash
+            .as_ref()
+            .is_some_and(|hash| hash.md5 || hash.content_md5);
+    let before_upload = config
+        .upload_hash
+        .as_ref()
+        .is_some_and(|hash| hash.before_upload());
+    // the digests are computed as the data is sent, unless the dedup preflight
+    // or the metadata post need them before
+    let mut hasher = None;
+    if info.has_data() && (config.upload_dedup_url.is_some() || before_upload) {
+        let (sha256, md5) = content_hash::hash_file(&info.path, md5)
+            .await
+            .map_err(|e| {
+                UploadFailure::new(UploadFailStage::ReadFile, format!("hash file fail:{e}"))
+            })?;
+        info.sha256 = Some(sha256);
+        info.md5 = md5;
+    } else if info.has_data() && (config.upload_hash.is_some() || config.upload_verify.is_some()) {
+        hasher = Some(ContentHasher::shared(md5));
+    }
+    let info = &*info;
+    let mut body = info.clone().to_body(config).map_err(|e| {
+        UploadFailure::new(
+            UploadFailStage::Metadata,
+            format!("build metadata of {:?} fail:{e}", info.relative_path),
+        )
+    })?;
+    let multipart = config
+        .upload_multipart
+        .clone()
+        .filter(|multipart| info.has_data() && multipart.is_applicable(info.size));
+    if let Some(multipart) = &multipart {
+        body.as_object_mut().expect("can't be here").insert(
+            "multipart".to_string(),
+            json!({
+                "part_size": multipart.part_size,
+                "part_count": multipart.part_count(info.size),
+            }),
+        );
+    }
+    info!("file.body:{}", body);
+    let key = idempotency_key(info);
+    if !info.has_data() {
+        // empty dir or link, only the metadata is uploaded
+        post_metadata(info, &config.upload_metadata_url, &body, Some(&key), ctx).await?;
+        return Ok(UploadOutcome::Success);
+    }
+    if let Some(dedup_url) = &config.upload_dedup_url {
+        if is_deduplicated(info, dedup_url, &body, ctx).await {
+            return Ok(UploadOutcome::Deduplicated);
+        }
+    }
+    let upload_result =
+        post_metadata(info, &config.upload_metadata_url, &body, Some(&key), ctx).await?;
+    let multipart_init = multipart.and_then(|multipart| {
+        TardisFuns::json
+            .str_to_obj::<MultipartUploadInit>(&upload_result)
+            .ok()
+            .map(|init| (multipart, init))
+    });
+    let single_init = match &multipart_init {
+        Some((_, init)) => {
+            for url in init
+                .part_urls
+                .iter()
+                .chain([&init.complete_url])
+                .chain(&init.abort_url)
+                .chain(&init.head_url)
+            {
+                check_returned_url(url, ctx)?;
+            }
+            None
+        }
+        None => {
+            let init = SingleUploadInit::from_response(upload_result);
+            for url in [&init.upload_url].into_iter().chain(&init.head_url) {
+                check_returned_url(url, ctx)?;
+            }
+            Some(init)
+        }
+    };
+    let abort_url = multipart_init
+        .as_ref()
+        .and_then(|(_, init)| init.abort_url.clone())
+        .or_else(|| config.upload_abort_url.clone());
+    if let Some(abort_url) = abort_url {
+        let mut abort_body = body.clone();
+        if let Some((_, init)) = &multipart_init {
+            abort_body
+                .as_object_mut()
+                .expect("can't be here")
+                .insert("upload_id".to_string(), json!(init.upload_id));
+        }
+        *pending.lock().unwrap() = Some((abort_url, abort_body));
+    }
+    match multipart_init {
+        Some((multipart, init)) => {
+            info!("multipart upload_id:{:?}", init.upload_id);
+            multipart_upload(info, &multipart, init, hasher.as_ref(), ctx).await?
+        }
+        None => {
+            let init = single_init.expect("can't be here");
+            put_file(info, init, hasher.as_ref(), ctx).await?
+        }
+    }
+    // the data is stored, nothing to abort anymore
+    pending.lock().unwrap().take();
+    if let Some(digest_url) = config
+        .upload_hash
+        .as_ref()
+        .and_then(|hash| hash.digest_url.as_ref())
+    {
+        let info = hashed_info(info, hasher.as_ref()).await?;
+        let mut req = json!({ "metadata": body });
+        for filed in [
+            UploadFileInfoFiled::Sha256,
+            UploadFileInfoFiled::Md5,
+            UploadFileInfoFiled::Size,
+        ] {
+            if let Some(filed_value) = info.get_value_by_map(filed.clone()) {
+                req.as_object_mut()
+                    .expect("can't be here")
+                    .insert(filed.name(config), filed_value);
+            }
+        }
+        post_metadata(&info, digest_url, &req, None, ctx).await?;
+    }
+    Ok(UploadOutcome::Success)
+}
+
+// `info` with the digests of its sent content.
+// The file is read again only if the hasher gave up on parts sent too far out of order.
+async fn hashed_info(
+    info: &UploadFileInfo,
+    hasher: Option<&SharedHasher>,
+) -> Result<UploadFileInfo, UploadFailure> {
+    let mut info = info.clone();
+    // hashed before the upload otherwise
+    let Some(hasher) = hasher else {
+        return Ok(info);
+    };
+    let (digests, md5) = {
+        let hasher = hasher.lock().unwrap();
+        (hasher.digests(info.size), hasher.has_md5())
+    };
+    let (sha256, md5) = match digests {
+        Some(digests) => digests,
+        None => content_hash::hash_file(&info.path, md5)
+            .await
+            .map_err(|e| {
+                UploadFailure::new(UploadFailStage::ReadFile, format!("hash file fail:{e}"))
+            })?,
+    };
+    info.sha256 = Some(sha256);
+    info.md5 = md5;
+    Ok(info)
+}
+
+// A url returned by the server out of the allow-list fails the whole batch, no data is sent to it
+fn check_returned_url(url: &str, ctx: &UploadContext) -> Result<(), UploadFailure> {
+    // an invalid url only fails its file when used
+    if reqwest::Url::parse(url).is_err() {
+        return Ok(());
+    }
+    host_policy::check(url, &ctx.allowed_hosts).map_err(|e| {
+        error!("{}, the batch is cancelled", e.message);
+        ctx.control.cancel.cancel();
+        UploadFailure::new(UploadFailStage::UploadUrl, e.message)
+    })
+}
+
+// Dedup preflight, a failed one only means the data is sent
+async fn is_deduplicated(
+    info: &UploadFileInfo,
+    dedup_url: &str,
+    body: &Value,
+    ctx: &UploadContext,
+) -> bool {
+    let Some(sha256) = info.sha256.clone() else {
+        return false;
+    };
+    let req = UploadDedupReq {
+        sha256,
+        size: info.size,
+        metadata: body.clone(),
+    };
+    match post_json(dedup_url, &req, ctx).await {
+        Ok(response) if response.status().as_u16() == 200 => {
+            match response
+                .text()
+                .await
+                .map(|body| TardisFuns::json.str_to_obj::<UploadDedupResp>(&body))
+            {
+                Ok(Ok(dedup_resp)) => dedup_resp.exists,
+                _ => {
+                    log::warn!("invalid dedup response of {:?}", info.relative_path);
+                    false
+                }
+            }
+        }
+        Ok(response) => {
+            log::warn!(
+                "dedup {:?} fail, status:{}",
+                info.relative_path,
+                response.status()
+            );
+            false
+        }
+        Err(e) => {
+            log::warn!("dedup {:?} fail:{e}", info.relative_path);
+            false
+        }
     }
 }
 
-async fn async_get_files(file_uri: &str) -> TardisResult<Vec<PathBuf>> {
-    let mut result = vec![];
-    let path = PathBuf::from(file_uri);
-    if path.is_file() {
-        result.push(path);
+// Entries found unreadable by the scan are queued too, so that they fail here before any request
+async fn check_readable(info: &UploadFileInfo) -> Result<(), UploadFailure> {
+    let result = match info.mime_type.as_str() {
+        DIR_MIME_TYPE => read_dir(&info.path).await.map(|_| ()),
+        LINK_MIME_TYPE => fs::symlink_metadata(&info.path).await.map(|_| ()),
+        _ => File::open(&info.path).await.map(|_| ()),
+    };
+    result.map_err(|e| {
+        UploadFailure::new(
+            UploadFailStage::ReadFile,
+            format!("read {:?} fail:{e}", info.relative_path),
+        )
+    })
+}
+
+async fn abort_upload(
+    info: &UploadFileInfo,
+    abort_url: String,
+    abort_body: &Value,
+    ctx: &UploadContext,
+) {
+    match post_json(&abort_url, abort_body, ctx).await {
+        Ok(abort_result) => info!(
+            "abort {:?} status:{}",
+            info.relative_path,
+            abort_result.status()
+        ),
+        Err(e) => error!("abort {:?} fail:{e}", info.relative_path),
+    }
+}
+
+// New for each upload of a file, a later retry or batch must not get the response of an earlier
+// upload, whose upload url may have expired
+fn idempotency_key(info: &UploadFileInfo) -> String {
+    format!("{}-{:016x}", info.id, random::<u64>())
+}
+
+// Returns the body of the response, for `upload_metadata_url` the upload url or the multipart upload init.
+// A post timing out may still have been registered, the retries carry the same idempotency key
+// so that the server doesn't register the file twice.
+async fn post_metadata<T: Serialize>(
+    info: &UploadFileInfo,
+    url: &str,
+    body: &T,
+    idempotency_key: Option<&str>,
+    ctx: &UploadContext,
+) -> Result<String, UploadFailure> {
+    let retry = &ctx.retry;
+    let mut attempt = 1;
+    loop {
+        let mut request = json_request(url, body, ctx);
+        if let Some(idempotency_key) = idempotency_key {
+            request = request.header(IDEMPOTENCY_KEY_HEADER, idempotency_key);
+        }
+        let failure = match request.send().await {
+            Ok(response) => {
+                let code = response.status().as_u16();
+                let upload_metadata_result = response.text().await;
+                info!("upload_metadata_result:{code} {:?}", upload_metadata_result);
+                if code == 200 {
+                    return upload_metadata_result.map_err(|e| {
+                        UploadFailure::network(
+                            UploadFailStage::Metadata,
+                            format!("read upload metadata response fail:{e}"),
+                        )
+                    });
+                }
+                let failure = UploadFailure::status(
+                    UploadFailStage::Metadata,
+                    code,
+                    upload_metadata_result.unwrap_or_default(),
+                    retry,
+                );
+                if !failure.retryable {
+                    return Err(failure);
+                }
+                failure
+            }
+            Err(e) => UploadFailure::network(
+                UploadFailStage::Metadata,
+                format!("upload metadata fail:{e}"),
+            ),
+        };
+        if !wait_retry(info, attempt, &failure, ctx).await {
+            return Err(failure);
+        }
+        attempt += 1;
+    }
+}
+
+async fn put_file(
+    info: &UploadFileInfo,
+    init: SingleUploadInit,
+    hasher: Option<&SharedHasher>,
+    ctx: &UploadContext,
+) -> Result<(), UploadFailure> {
+    let retry = &ctx.retry;
+    let upload_url = init.upload_url;
+    info!("upload_url:{:?}", upload_url);
+    if reqwest::Url::parse(&upload_url).is_err() {
+        return Err(UploadFailure::new(
+            UploadFailStage::UploadUrl,
+            format!("invalid upload url:{upload_url}"),
+        ));
+    }
+    let content_md5 = ctx
+        .config
+        .upload_hash
+        .as_ref()
+        .filter(|hash| hash.content_md5)
+        .and_then(|_| info.md5.as_ref())
+        .and_then(|md5| hex::decode(md5).ok())
+        .map(|md5| general_purpose::STANDARD.encode(md5));
+    let client = &ctx.client;
+    let mut attempt = 1;
+    loop {
+        // opened only now so that queued files don't hold file handles,
+        // and again for each attempt as the body stream is consumed by it
+        let file = File::open(&info.path).await.map_err(|e| {
+            UploadFailure::new(UploadFailStage::ReadFile, format!("open file fail:{e}"))
+        })?;
+        let mut request = client
+            .put(upload_url.clone())
+            .header(reqwest::header::CONTENT_LENGTH, info.size);
+        if let Some(content_md5) = &content_md5 {
+            request = request.header("Content-MD5", content_md5);
+        }
+        let failure = match request.body(upload_body(file, 0, hasher, ctx)).send().await {
+            Ok(response) if response.status().is_success() => {
+                match verify_upload(
+                    info,
+                    hasher,
+                    init.head_url.as_deref(),
+                    response.headers(),
+                    ctx,
+                )
+                .await
+                {
+                    Ok(()) => return Ok(()),
+                    Err(failure) if !failure.retryable => return Err(failure),
+                    Err(failure) => failure,
+                }
+            }
+            Ok(response) => {
+                let failure =
+                    UploadFailure::from_response(UploadFailStage::Upload, response, retry).await;
+                if !failure.retryable {
+                    return Err(failure);
+                }
+                failure
+            }
+            Err(e) => UploadFailure::network(UploadFailStage::Upload, format!("upload fail:{e}")),
+        };
+        if !wait_retry(info, attempt, &failure, ctx).await {
+            return Err(failure);
+        }
+        attempt += 1;
+    }
+}
+
+// Integrity check of an upload if the params ask for it, with the stored object at `head_url` if
+// `upload_verify.head` is set or else the headers of the response of the put or the complete.
+// A mismatch of a single put is retried, an object the storage reports nothing checkable of fails
+async fn verify_upload(
+    info: &UploadFileInfo,
+    hasher: Option<&SharedHasher>,
+    head_url: Option<&str>,
+    response_headers: &reqwest::header::HeaderMap,
+    ctx: &UploadContext,
+) -> Result<(), UploadFailure> {
+    let Some(verify) = &ctx.config.upload_verify else {
+        return Ok(());
+    };
+    let info = &hashed_info(info, hasher).await?;
+    let result = if let Some(head_url) = head_url.filter(|_| verify.head) {
+        match ctx.client.head(head_url).send().await {
+            Ok(response) if response.status().is_success() => {
+                integrity::check_object(info, response.headers(), true)
+            }
+            Ok(response) => {
+                return Err(UploadFailure::from_response(
+                    UploadFailStage::Verify,
+                    response,
+                    &ctx.retry,
+                )
+                .await)
+            }
+            Err(e) => {
+                return Err(UploadFailure::network(
+                    UploadFailStage::Verify,
+                    format!("head uploaded object fail:{e}"),
+                ))
+            }
+        }
     } else {
-        let mut dir = read_dir(file_uri).await.expect("can't open dir");
-        result.push(path);
-        let mut push_dir = true;
-        while let Some(d) = dir
-            .next_entry()
+        integrity::check_object(info, response_headers, false)
+    };
+    result.map_err(|failure| {
+        error!("verify {:?} fail:{failure}", info.relative_path);
+        UploadFailure {
+            retryable: matches!(failure, integrity::CheckFailure::Mismatch(_)),
+            ..UploadFailure::new(UploadFailStage::Verify, failure.to_string())
+        }
+    })
+}
+
+// Returns false if no attempt is left, otherwise reports the retry and waits for the backoff
+async fn wait_retry(
+    info: &UploadFileInfo,
+    attempt: u32,
+    failure: &UploadFailure,
+    ctx: &UploadContext,
+) -> bool {
+    let retry = &ctx.retry;
+    if attempt >= retry.max_attempts {
+        error!(
+            "{:?} fail after {attempt} attempts:{failure}",
+            info.relative_path
+        );
+        return false;
+    }
+    log::warn!("{:?} attempt {attempt} fail:{failure}", info.relative_path);
+    let _ = ctx
+        .tx
+        .send(UploadEvent::Retry(UploadRetryProgress {
+            file_id: info.id.clone(),
+            attempt: attempt + 1,
+            max_attempts: retry.max_attempts,
+            reason: failure.to_string(),
+        }))
+        .await;
+    tardis::tokio::time::sleep(retry.backoff(attempt)).await;
+    true
+}
+
+async fn multipart_upload(
+    info: &UploadFileInfo,
+    multipart: &MultipartUploadParams,
+    init: MultipartUploadInit,
+    hasher: Option<&SharedHasher>,
+    ctx: &UploadContext,
+) -> Result<(), UploadFailure> {
+    let total_parts = init.part_urls.len();
+    if total_parts as u64 != multipart.part_count(info.size) {
+        return Err(UploadFailure::new(
+            UploadFailStage::UploadUrl,
+            format!(
+                "multipart part count mismatch, expect {} but got {}",
+                multipart.part_count(info.size),
+                total_parts
+            ),
+        ));
+    }
+    let mut parts_stream = stream::iter(init.part_urls.into_iter().enumerate())
+        .map(|(index, part_url)| {
+            let offset = index as u64 * multipart.part_size;
+            let len = multipart.part_size.min(info.size - offset);
+            async move {
+                let etag = put_part(info, part_url, offset, len, hasher, ctx).await;
+                (index + 1, etag)
+            }
+        })
+        .buffer_unordered(multipart.concurrent.max(1));
+    let mut parts = Vec::with_capacity(total_parts);
+    while let Some((part_number, etag)) = parts_stream.next().await {
+        let etag = etag.map_err(|failure| UploadFailure {
+            message: format!("part {part_number}: {}", failure.message),
+            ..failure
+        })?;
+        parts.push(MultipartUploadPart { part_number, etag });
+        let _ = ctx
+            .tx
+            .send(UploadEvent::Part(UploadPartProgress {
+                file_id: info.id.clone(),
+                part_number,
+                uploaded_parts: parts.len(),
+                total_parts,
+            }))
+            .await;
+    }
+    parts.sort_by_key(|part| part.part_number);
+
+    match post_json(
+        &init.complete_url,
+        &MultipartUploadComplete {
+            upload_id: init.upload_id,
+            parts,
+        },
+        ctx,
+    )
+    .await
+    {
+        Ok(response) => {
+            info!("multipart complete status:{}", response.status());
+            if response.status().as_u16() == 200 {
+                verify_upload(
+                    info,
+                    hasher,
+                    init.head_url.as_deref(),
+                    response.headers(),
+                    ctx,
+                )
+                .await
+            } else {
+                Err(
+                    UploadFailure::from_response(UploadFailStage::Complete, response, &ctx.retry)
+                        .await,
+                )
+            }
+        }
+        Err(e) => Err(UploadFailure::network(
+            UploadFailStage::Complete,
+            format!("multipart complete fail:{e}"),
+        )),
+    }
+}
+
+// Returns the ETag of the uploaded part
+async fn put_part(
+    info: &UploadFileInfo,
+    part_url: String,
+    offset: u64,
+    len: u64,
+    hasher: Option<&SharedHasher>,
+    ctx: &UploadContext,
+) -> Result<String, UploadFailure> {
+    let retry = &ctx.retry;
+    let client = &ctx.client;
+    let mut attempt = 1;
+    loop {
+        let mut file = File::open(&info.path).await.map_err(|e| {
+            UploadFailure::new(UploadFailStage::ReadFile, format!("open file fail:{e}"))
+        })?;
+        file.seek(SeekFrom::Start(offset)).await.map_err(|e| {
+            UploadFailure::new(UploadFailStage::ReadFile, format!("seek file fail:{e}"))
+        })?;
+        let failure = match client
+            .put(part_url.clone())
+            .header(reqwest::header::CONTENT_LENGTH, len)
+            .body(upload_body(file.take(len), offset, hasher, ctx))
+            .send()
             .await
-            .map_err(|e| TardisError::io_error(&format!("io error:{e}"), "error"))?
         {
-            match d.path().to_str() {
-                Some(path) => {
-                    if push_dir {
-                        result.remove(result.len() - 1);
-                        push_dir = false
-                    }
-                    result.append(&mut Box::pin(async_get_files(path)).await?);
+            Ok(response) if response.status().is_success() => {
+                let status = response.status().as_u16();
+                return response
+                    .headers()
+                    .get(reqwest::header::ETAG)
+                    .and_then(|etag| etag.to_str().ok())
+                    .map(|etag| etag.to_string())
+                    .ok_or_else(|| {
+                        UploadFailure::status(
+                            UploadFailStage::Upload,
+                            status,
+                            "part response has no ETag",
+                            retry,
+                        )
+                    });
+            }
+            Ok(response) => {
+                let failure =
+                    UploadFailure::from_response(UploadFailStage::Upload, response, retry).await;
+                if !failure.retryable {
+                    return Err(failure);
                 }
-                None => continue,
+                failure
+            }
+            Err(e) => {
+                UploadFailure::network(UploadFailStage::Upload, format!("upload part fail:{e}"))
+            }
+        };
+        if !wait_retry(info, attempt, &failure, ctx).await {
+            return Err(failure);
+        }
+        attempt += 1;
+    }
+}
+
+// Post `body` as json with the fixed headers of the params
+async fn post_json<T: Serialize>(
+    url: &str,
+    body: &T,
+    ctx: &UploadContext,
+) -> reqwest::Result<reqwest::Response> {
+    json_request(url, body, ctx).send().await
+}
+
+fn json_request<T: Serialize>(url: &str, body: &T, ctx: &UploadContext) -> reqwest::RequestBuilder {
+    let mut request = ctx
+        .client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json");
+    for (name, value) in ctx.config.upload_fixed_headers.iter().flatten() {
+        request = request.header(name, value);
+    }
+    request.body(serde_json::to_vec(body).expect("can't be here"))
+}
+
+// Body stream of the put of the data at `offset` of the file, suspended while the batch is paused
+fn upload_body<R: AsyncRead + Send + 'static>(
+    reader: R,
+    offset: u64,
+    hasher: Option<&SharedHasher>,
+    ctx: &UploadContext,
+) -> reqwest::Body {
+    let paused = ctx.control.paused.clone();
+    let hasher = hasher.cloned();
+    let mut position = offset;
+    let stream = ReaderStream::new(reader).inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            if let Some(hasher) = &hasher {
+                hasher.lock().unwrap().update(position, chunk);
+            }
+            position += chunk.len() as u64;
+        }
+    });
+    reqwest::Body::wrap_stream(stream.then(move |chunk| {
+        let mut paused = paused.clone();
+        async move {
+            wait_resumed(&mut paused).await;
+            chunk
+        }
+    }))
+}
+
+async fn wait_resumed(paused: &mut watch::Receiver<bool>) {
+    while *paused.borrow_and_update() {
+        if paused.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+// Walk `path` and queue what is found, an empty directory is queued as a directory entry.
+// Entries that can't be read are queued as well, so that they are reported as failed files.
+async fn async_get_files(path: &Path, base_path: &Path, scan: &mut FileScan) -> TardisResult<()> {
+    let metadata = match fs::symlink_metadata(path).await {
+        Ok(metadata) if metadata.is_symlink() => match scan.filter.symlink() {
+            SymlinkPolicy::Skip => {
+                scan.report(
+                    path,
+                    base_path,
+                    false,
+                    UploadExcludeReason::Symlink,
+                    String::new(),
+                );
+                return Ok(());
+            }
+            SymlinkPolicy::Link => {
+                let info = UploadFileInfo {
+                    name: file_name(path),
+                    mime_type: LINK_MIME_TYPE.to_string(),
+                    link_target: fs::read_link(path).await.ok(),
+                    ..scanned_info(path, base_path)?
+                };
+                scan.push(info).await;
+                return Ok(());
+            }
+            // a broken link is queued as a file that can't be read
+            SymlinkPolicy::Follow => fs::metadata(path).await.ok(),
+        },
+        Ok(metadata) => Some(metadata),
+        Err(e) => {
+            log::warn!("read metadata of {path:?} fail:{e}");
+            None
+        }
+    };
+    match metadata {
+        Some(metadata) if metadata.is_dir() => {
+            Box::pin(async_get_dir_files(path, base_path, scan)).await
+        }
+        Some(metadata) if !metadata.is_file() => {
+            scan.report(
+                path,
+                base_path,
+                false,
+                UploadExcludeReason::SpecialFile,
+                String::new(),
+            );
+            Ok(())
+        }
+        metadata => {
+            let by_extension = mime_infer::from_path(path)
+                .first()
+                .map(|mime| mime.to_string());
+            let mut info = UploadFileInfo {
+                name: file_name(path),
+                mime_type: choose_mime(MimeSource::Extension, None, by_extension.as_deref(), false),
+                mime_type_by_content: None,
+                mime_type_by_extension: by_extension,
+                size: metadata.map(|metadata| metadata.len()).unwrap_or_default(),
+                ..scanned_info(path, base_path)?
             };
+            if let Some(mime_source) = scan.sniff {
+                sniff_info(&mut info, mime_source).await;
+            }
+            scan.push(info).await;
+            Ok(())
         }
     }
+}
+
+async fn async_get_dir_files(
+    path: &Path,
+    base_path: &Path,
+    scan: &mut FileScan,
+) -> TardisResult<()> {
+    let real_path = fs::canonicalize(path)
+        .await
+        .unwrap_or_else(|_| path.to_path_buf());
+    if scan.ancestors.contains(&real_path) {
+        scan.report(
+            path,
+            base_path,
+            true,
+            UploadExcludeReason::SymlinkCycle,
+            real_path.display().to_string(),
+        );
+        return Ok(());
+    }
+    let mut dir = match read_dir(path).await {
+        Ok(dir) => dir,
+        Err(e) => {
+            log::warn!("read dir {path:?} fail:{e}");
+            // fails when its upload finds it unreadable
+            scan.push(scanned_info(path, base_path)?).await;
+            return Ok(());
+        }
+    };
+    scan.enter_dir(path, base_path, real_path).await;
+    let is_empty = async_scan_entries(&mut dir, path, base_path, scan).await;
+    // on every exit, the ignore rules of the dir must not leak to its siblings
+    scan.leave_dir();
+    if is_empty? {
+        scan.push(scanned_info(path, base_path)?).await;
+    }
+
+    Ok(())
+}
+
+// Returns whether the dir is empty
+async fn async_scan_entries(
+    dir: &mut fs::ReadDir,
+    path: &Path,
+    base_path: &Path,
+    scan: &mut FileScan,
+) -> TardisResult<bool> {
+    let mut is_empty = true;
+    loop {
+        let d = match dir.next_entry().await {
+            Ok(Some(d)) => d,
+            Ok(None) => break,
+            Err(e) => {
+                log::warn!("read dir {path:?} fail:{e}");
+                break;
+            }
+        };
+        if scan.is_stopped() {
+            return Ok(false);
+        }
+        is_empty = false;
+        let entry_path = d.path();
+        let is_dir = match d.file_type().await {
+            // followed as `Path::is_dir` does
+            Ok(file_type) if file_type.is_symlink() => fs::metadata(&entry_path)
+                .await
+                .is_ok_and(|metadata| metadata.is_dir()),
+            Ok(file_type) => file_type.is_dir(),
+            Err(_) => false,
+        };
+        if scan.exclude(&entry_path, base_path, is_dir) {
+            continue;
+        }
+        async_get_files(&entry_path, base_path, scan).await?;
+    }
+    Ok(is_empty)
+}
+
+// Mime type from the magic bytes of the head of the file and whether the head is valid utf-8,
+// an unreadable file is left to fail at its upload
+async fn sniff_mime(path: &Path) -> (Option<String>, bool) {
+    let mut head = Vec::with_capacity(SNIFF_SIZE);
+    let read = match File::open(path).await {
+        Ok(file) => file.take(SNIFF_SIZE as u64).read_to_end(&mut head).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = read {
+        log::warn!("sniff mime type of {path:?} fail:{e}");
+        return (None, false);
+    }
+    let by_content = infer::get(&head).map(|kind| kind.mime_type().to_string());
+    (by_content, is_text(&head))
+}
+
+// The head may end in the middle of a character
+fn is_text(head: &[u8]) -> bool {
+    match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none() && head.len() - e.valid_up_to() < 4,
+    }
+}
+
+// Choose the mime type with the content guess, the scan only has the extension one
+async fn sniff_info(info: &mut UploadFileInfo, mime_source: MimeSource) {
+    let (by_content, is_text) = sniff_mime(&info.path).await;
+    info.mime_type = choose_mime(
+        mime_source,
+        by_content.as_deref(),
+        info.mime_type_by_extension.as_deref(),
+        is_text,
+    );
+    info.mime_type_by_content = by_content;
+}
+
+fn choose_mime(
+    source: MimeSource,
+    by_content: Option<&str>,
+    by_extension: Option<&str>,
+    is_text: bool,
+) -> String {
+    let preferred = match source {
+        MimeSource::Content => by_content.or(by_extension),
+        MimeSource::Extension => by_extension.or(by_content),
+    };
+    match preferred {
+        Some(mime) => mime.to_string(),
+        None if is_text => "text/plain".to_string(),
+        None => "application/octet-stream".to_string(),
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+// Info of a scanned path, as an empty directory unless filled in
+fn scanned_info(path: &Path, base_path: &Path) -> TardisResult<UploadFileInfo> {
+    let relative_path = path
+        .strip_prefix(base_path)
+        .map_err(|e| TardisError::io_error(&format!("io error:{e}"), "error"))?;
+    Ok(UploadFileInfo {
+        name: "".to_string(),
+        relative_path: relative_path.to_path_buf(),
+        size: 0,
+        mime_type: DIR_MIME_TYPE.to_string(),
+        mime_type_by_content: None,
+        mime_type_by_extension: None,
+        sha256: None,
+        md5: None,
+        link_target: None,
+        id: file_id(path),
+        path: path.to_path_buf(),
+    })
+}
+
+#[test]
+fn test_single_upload_init() {
+    let init = SingleUploadInit::from_response("https://storage/obj?sig=1".to_string());
+    assert_eq!(init.upload_url, "https://storage/obj?sig=1");
+    assert_eq!(init.head_url, None);
+    let init = SingleUploadInit::from_response(
+        r#"{"upload_url":"https://storage/put","head_url":"https://storage/head"}"#.to_string(),
+    );
+    assert_eq!(init.upload_url, "https://storage/put");
+    assert_eq!(init.head_url.as_deref(), Some("https://storage/head"));
+}
+
+#[test]
+fn test_multipart_upload_params() {
+    let multipart = MultipartUploadParams {
+        threshold: 100,
+        part_size: 40,
+        concurrent: 3,
+    };
+    assert!(!multipart.is_applicable(0));
+    assert!(!multipart.is_applicable(100));
+    assert!(multipart.is_applicable(101));
+    assert_eq!(multipart.part_count(101), 3);
+    assert_eq!(multipart.part_count(120), 3);
+    assert_eq!(multipart.part_count(121), 4);
+    // a part size of 0 would never end
+    let multipart = MultipartUploadParams {
+        part_size: 0,
+        ..multipart
+    };
+    assert!(!multipart.is_applicable(1000));
+}
+
+#[test]
+fn test_choose_mime() {
+    let png = Some("image/png");
+    let jpg = Some("image/jpeg");
+    assert_eq!(
+        choose_mime(MimeSource::Content, png, jpg, false),
+        "image/png"
+    );
+    assert_eq!(
+        choose_mime(MimeSource::Extension, png, jpg, false),
+        "image/jpeg"
+    );
+    // the other guess is used if the preferred one is missing
+    assert_eq!(
+        choose_mime(MimeSource::Content, None, jpg, false),
+        "image/jpeg"
+    );
+    assert_eq!(
+        choose_mime(MimeSource::Extension, png, None, false),
+        "image/png"
+    );
+    assert_eq!(
+        choose_mime(MimeSource::Content, None, None, true),
+        "text/plain"
+    );
+    assert_eq!(
+        choose_mime(MimeSource::Extension, None, None, false),
+        "application/octet-stream"
+    );
+}
 
-    Ok(result)
+#[test]
+fn test_is_text() {
+    assert!(is_text(b""));
+    assert!(is_text("plain text, héllo".as_bytes()));
+    // cut in the middle of `é`
+    let text = "héllo".as_bytes();
+    assert!(is_text(&text[..2]));
+    assert!(!is_text(b"\xff\xfe\x00binary"));
+    assert!(!is_text(&[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]));
+    // an invalid sequence at the end is not a cut character
+    assert!(!is_text(b"text\xc3\x28"));
 }
@@ -0,0 +1,279 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::{path_codec, uploader::UploadFileInfo};
+
+// Per-file violations returned to the web page, the count is still reported beyond it
+const MAX_VIOLATIONS: usize = 1000;
+
+/// Limits set by the calling page, checked against the whole batch before any upload.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct UploadValidation {
+    pub max_file_size: Option<u64>,
+    pub max_total_size: Option<u64>,
+    pub max_file_count: Option<usize>,
+    // e.g. `application/pdf` or `image/*`
+    pub allowed_mime_types: Option<Vec<String>>,
+    pub denied_mime_types: Option<Vec<String>>,
+    // without the leading dot, case insensitive
+    pub allowed_extensions: Option<Vec<String>>,
+    pub denied_extensions: Option<Vec<String>>,
+    // a picked file has depth 1, a file in a picked folder 2, ...
+    pub max_path_depth: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UploadViolationRule {
+    FileSize,
+    TotalSize,
+    FileCount,
+    MimeType,
+    Extension,
+    PathDepth,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UploadViolation {
+    pub rule: UploadViolationRule,
+    // none for the rules on the whole batch
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "path_codec::lossy::option"
+    )]
+    pub relative_path: Option<PathBuf>,
+    pub message: String,
+}
+
+impl UploadValidation {
+    /// Whether the content of the files must be sniffed, see [`crate::processor_config::MimeSource`].
+    pub fn checks_mime(&self) -> bool {
+        self.allowed_mime_types.is_some() || self.denied_mime_types.is_some()
+    }
+
+    /// The rules broken by a single file, the ones on the whole batch are left to [`Validator`].
+    pub fn file_violations(&self, info: &UploadFileInfo) -> Vec<UploadViolation> {
+        let mut violations = vec![];
+        let mut violate = |rule, message| {
+            violations.push(UploadViolation {
+                rule,
+                relative_path: Some(info.relative_path.clone()),
+                message,
+            })
+        };
+        let depth = info.relative_path.components().count();
+        if let Some(max_path_depth) = self.max_path_depth {
+            if depth > max_path_depth {
+                violate(
+                    UploadViolationRule::PathDepth,
+                    format!("path depth {depth} exceeds {max_path_depth}"),
+                );
+            }
+        }
+        // directories and links carry no data
+        if !info.has_data() {
+            return violations;
+        }
+        if let Some(max_file_size) = self.max_file_size {
+            if info.size > max_file_size {
+                violate(
+                    UploadViolationRule::FileSize,
+                    format!("size {} exceeds {max_file_size}", info.size),
+                );
+            }
+        }
+        let mime_type = &info.mime_type;
+        if !is_allowed(
+            &self.allowed_mime_types,
+            &self.denied_mime_types,
+            |pattern| mime_matches(pattern, mime_type),
+        ) {
+            violate(
+                UploadViolationRule::MimeType,
+                format!("mime type {mime_type} is not allowed"),
+            );
+        }
+        let extension = info
+            .relative_path
+            .extension()
+            .map(|extension| extension.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if !is_allowed(
+            &self.allowed_extensions,
+            &self.denied_extensions,
+            |pattern| {
+                pattern
+                    .trim_start_matches('.')
+                    .eq_ignore_ascii_case(&extension)
+            },
+        ) {
+            violate(
+                UploadViolationRule::Extension,
+                format!("extension {extension:?} is not allowed"),
+            );
+        }
+        violations
+    }
+}
+
+/// Checks the files of a batch as they are found, see [`UploadValidation`].
+pub struct Validator {
+    rules: UploadValidation,
+    file_numbers: usize,
+    file_size: u64,
+    violation_numbers: usize,
+    violations: Vec<UploadViolation>,
+}
+
+impl Validator {
+    pub fn new(rules: UploadValidation) -> Self {
+        Validator {
+            rules,
+            file_numbers: 0,
+            file_size: 0,
+            violation_numbers: 0,
+            violations: vec![],
+        }
+    }
+
+    pub fn check(&mut self, info: &UploadFileInfo) {
+        // directories and links carry no data
+        if info.has_data() {
+            self.file_numbers += 1;
+            self.file_size += info.size;
+        }
+        for violation in self.rules.file_violations(info) {
+            self.violation_numbers += 1;
+            if self.violations.len() < MAX_VIOLATIONS {
+                self.violations.push(violation);
+            }
+        }
+    }
+
+    /// The violations of the batch, empty if it can be uploaded.
+    pub fn finish(mut self) -> Vec<UploadViolation> {
+        if let Some(max_file_count) = self.rules.max_file_count {
+            if self.file_numbers > max_file_count {
+                self.violations.push(UploadViolation {
+                    rule: UploadViolationRule::FileCount,
+                    relative_path: None,
+                    message: format!("{} files exceed {max_file_count}", self.file_numbers),
+                });
+            }
+        }
+        if let Some(max_total_size) = self.rules.max_total_size {
+            if self.file_size > max_total_size {
+                self.violations.push(UploadViolation {
+                    rule: UploadViolationRule::TotalSize,
+                    relative_path: None,
+                    message: format!("total size {} exceeds {max_total_size}", self.file_size),
+                });
+            }
+        }
+        if self.violation_numbers > MAX_VIOLATIONS {
+            log::warn!(
+                "{} violations, only the first {MAX_VIOLATIONS} are reported",
+                self.violation_numbers
+            );
+        }
+        self.violations
+    }
+}
+
+fn is_allowed(
+    allowed: &Option<Vec<String>>,
+    denied: &Option<Vec<String>>,
+    matches: impl Fn(&str) -> bool,
+) -> bool {
+    if denied
+        .iter()
+        .flatten()
+        .any(|pattern| matches(pattern.as_str()))
+    {
+        return false;
+    }
+    allowed
+        .as_ref()
+        .is_none_or(|allowed| allowed.iter().any(|pattern| matches(pattern)))
+}
+
+// `type/*` matches all the subtypes
+fn mime_matches(pattern: &str, mime_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(top) => mime_type
+            .split_once('/')
+            .is_some_and(|(mime_top, _)| mime_top.eq_ignore_ascii_case(top)),
+        None => pattern.eq_ignore_ascii_case(mime_type),
+    }
+}
+
+#[test]
+fn test_validator() {
+    let file = |relative_path: &str, size: u64, mime_type: &str| UploadFileInfo {
+        id: String::new(),
+        name: String::new(),
+        relative_path: PathBuf::from(relative_path),
+        size,
+        mime_type: mime_type.to_string(),
+        mime_type_by_content: None,
+        mime_type_by_extension: None,
+        sha256: None,
+        md5: None,
+        link_target: None,
+        path: PathBuf::from(relative_path),
+    };
+    let violations = |rules: UploadValidation, files: &[UploadFileInfo]| {
+        let mut validator = Validator::new(rules);
+        for info in files {
+            validator.check(info);
+        }
+        validator
+            .finish()
+            .into_iter()
+            .map(|violation| violation.rule)
+            .collect::<Vec<_>>()
+    };
+    let files = [
+        file("docs/a.pdf", 100, "application/pdf"),
+        file("docs/b.PNG", 300, "image/png"),
+        file("docs/sub/c.exe", 50, "application/x-msdownload"),
+    ];
+
+    assert!(violations(UploadValidation::default(), &files).is_empty());
+    assert_eq!(
+        violations(
+            UploadValidation {
+                max_file_size: Some(200),
+                max_total_size: Some(400),
+                max_file_count: Some(2),
+                ..Default::default()
+            },
+            &files
+        ),
+        vec![
+            UploadViolationRule::FileSize,
+            UploadViolationRule::FileCount,
+            UploadViolationRule::TotalSize
+        ]
+    );
+    assert_eq!(
+        violations(
+            UploadValidation {
+                allowed_mime_types: Some(vec![
+                    "application/pdf".to_string(),
+                    "image/*".to_string()
+                ]),
+                denied_extensions: Some(vec![".png".to_string()]),
+                max_path_depth: Some(2),
+                ..Default::default()
+            },
+            &files
+        ),
+        vec![
+            UploadViolationRule::Extension,
+            UploadViolationRule::PathDepth,
+            UploadViolationRule::MimeType
+        ]
+    );
+}